@@ -0,0 +1,600 @@
+// This file is part of rget.
+//
+// Copyright (C) 2016-2017 Arcterus (Alex Lyon) and rget contributors.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The HTTP client requests are sent with.
+//!
+//! Every connection the client uses is opened by `Connector`, so the proxy and TLS settings apply
+//! to all of them.  HTTPS servers are offered HTTP/2 on the first connection to them.  Requests to
+//! the servers that accept it are sent as streams over a few shared connections, the others are
+//! sent over HTTP/1.1 by hyper.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::rc::Rc;
+
+use bytes::Bytes;
+use futures::{future, Async, Future, Poll, Stream};
+use futures::unsync::oneshot;
+use h2::{self, RecvStream};
+use h2::client::SendRequest;
+use http;
+use hyper::{self, Chunk, Headers, HttpVersion, Method, Request, StatusCode, Uri};
+use hyper::client::{HttpConnector, Service};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::TlsStream;
+use url::Url;
+
+use error::{self, Error, ErrorReason};
+use network::DownloaderConfig;
+use proxy::{self, Connecting, ProxySettings};
+use tls::TlsSettings;
+
+/// The number of threads host names are resolved on.
+const DNS_THREADS: usize = 4;
+/// Headers that only apply to a single HTTP/1.1 connection, which HTTP/2 does not allow.
+const CONNECTION_HEADERS: &[&str] =
+   &["connection", "host", "keep-alive", "proxy-connection", "te", "transfer-encoding", "upgrade"];
+
+pub type FutureResponse = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+
+/// Sends requests, keeping their connections open to be reused by later requests.  Clones share
+/// the same connections.
+#[derive(Clone)]
+pub struct Client {
+   inner: hyper::Client<Connector>,
+   connector: Connector,
+   proxies: Rc<ProxySettings>,
+   pinned: bool,
+   /// How requests reach each HTTPS origin HTTP/2 was offered to.
+   origins: Rc<RefCell<HashMap<String, Origin>>>,
+   /// The most requests sent over one HTTP/2 connection at the same time.
+   streams_per_connection: usize
+}
+
+/// Opens the connections of a `Client`.
+#[derive(Clone)]
+pub struct Connector {
+   dns: HttpConnector,
+   handle: Handle,
+   proxies: Rc<ProxySettings>,
+   tls: TlsSettings,
+   /// Connections that were offered HTTP/2 but will speak HTTP/1.1, for hyper to send the next
+   /// request to their origin over.
+   parked: Rc<RefCell<HashMap<String, Vec<Conn>>>>
+}
+
+/// A connection opened by `Connector`.
+pub enum Conn {
+   Plain(TcpStream),
+   Tls(TlsStream<TcpStream>)
+}
+
+/// The response to a request sent by `Client`, whichever version of HTTP it was sent over.
+pub struct Response {
+   status: StatusCode,
+   version: HttpVersion,
+   headers: Headers,
+   body: Body
+}
+
+/// The body of a `Response`.
+pub struct Body(BodyStream);
+
+enum BodyStream {
+   Http1(hyper::Body),
+   Http2 {
+      body: RecvStream,
+      /// Keeps the place of the stream until the body is dropped, so no other request takes it
+      /// while the body is being read.
+      _slot: StreamSlot
+   }
+}
+
+/// How requests reach an HTTPS origin (`https://host:port`).
+enum Origin {
+   /// The first connection is being made, and these requests wait to learn what it speaks.
+   Negotiating(Vec<oneshot::Sender<()>>),
+   /// The server chose HTTP/1.1, so requests are sent by hyper.
+   Http1,
+   /// The server speaks HTTP/2 over these connections.
+   Http2(Vec<Rc<RefCell<Http2Connection>>>)
+}
+
+/// An HTTP/2 connection that requests are sent over as streams.
+struct Http2Connection {
+   /// Sends requests over the connection once its handshake is done.
+   send: Option<SendRequest<Bytes>>,
+   /// Requests waiting for the handshake.
+   waiting: Vec<oneshot::Sender<()>>,
+   /// Requests using the connection, including those still reading their response.
+   streams: usize,
+   open: bool
+}
+
+/// A request's place on an `Http2Connection`, given up when it is dropped.
+struct StreamSlot(Rc<RefCell<Http2Connection>>);
+
+/// What the server chose when HTTP/2 was offered to it.
+enum Negotiated {
+   Http1(Conn),
+   Http2(TlsStream<TcpStream>)
+}
+
+/// How `Client::execute` sends a request.
+enum Route {
+   Http1,
+   /// Wait for the first connection to the origin, then route the request again.
+   Wait(oneshot::Receiver<()>),
+   /// Make the first connection to the origin.
+   Negotiate,
+   /// Open a new HTTP/2 connection for the request.
+   Open(StreamSlot),
+   Http2(StreamSlot)
+}
+
+impl Client {
+   /// Creates a client that uses the proxy, TLS and HTTP/2 settings from `config` and runs on the
+   /// event loop of `handle`.
+   pub fn new(config: &DownloaderConfig, handle: &Handle) -> error::Result<Client> {
+      let proxies = Rc::new(ProxySettings::from_config(config)?);
+      let tls = TlsSettings::from_config(config)?;
+      let pinned = tls.pinned();
+      let connector = Connector {
+         dns: HttpConnector::new(DNS_THREADS, handle),
+         handle: handle.clone(),
+         proxies: proxies.clone(),
+         tls,
+         parked: Rc::new(RefCell::new(HashMap::new()))
+      };
+
+      Ok(Client {
+         inner: hyper::Client::configure().connector(connector.clone()).build(handle),
+         connector,
+         proxies,
+         pinned,
+         origins: Rc::new(RefCell::new(HashMap::new())),
+         streams_per_connection: config.max_streams_per_connection
+                                       .map_or(usize::MAX, |max| max.max(1) as usize)
+      })
+   }
+
+   /// Creates a GET request for `url`.  Fails if public keys are pinned but `url` does not use
+   /// HTTPS, as the key could not be checked.
+   pub fn get(&self, url: &Url) -> error::Result<Request> {
+      if self.pinned && url.scheme() != "https" {
+         return Err(Error::new(ErrorReason::InvalidConfig(
+                               "public key pinning requires an HTTPS URL")));
+      }
+      let uri = match url.as_str().parse::<Uri>() {
+         Ok(m) => m,
+         Err(f) => return Err(Error::new(ErrorReason::FailedRequest(f.into())))
+      };
+      let mut request = Request::new(Method::Get, uri);
+      if self.proxies.forwards(url) {
+         request.set_proxy(true);
+         if let Some(authorization) = self.proxies.forward_authorization(url) {
+            request.headers_mut().set_raw("Proxy-Authorization", authorization);
+         }
+      }
+      Ok(request)
+   }
+
+   /// Sends `request`.  Requests to HTTPS servers that chose HTTP/2 share its connections, with
+   /// a new connection opened once every other one carries as many requests as allowed.
+   pub fn execute(&self, request: Request) -> FutureResponse {
+      let origin = match self.http2_origin(&request) {
+         Some(origin) => origin,
+         None => return self.send_http1(request)
+      };
+      match self.route(&origin) {
+         Route::Http1 => self.send_http1(request),
+         Route::Wait(negotiated) => {
+            let client = self.clone();
+            Box::new(negotiated.then(move |_| client.execute(request)))
+         }
+         Route::Negotiate => self.negotiate(origin, request),
+         Route::Open(slot) => self.open_http2(origin, slot, request),
+         Route::Http2(slot) => self.send_http2(slot, request)
+      }
+   }
+
+   /// The origin of `request` if HTTP/2 is offered to it.
+   fn http2_origin(&self, request: &Request) -> Option<String> {
+      if !self.connector.tls.offers_http2() || request.uri().scheme() != Some("https") {
+         return None;
+      }
+      Url::parse(request.uri().as_ref()).ok().map(|url| origin_key(&url))
+   }
+
+   /// Decides how to send a request to `origin`, taking a place on an HTTP/2 connection for it.
+   fn route(&self, origin: &str) -> Route {
+      let mut origins = self.origins.borrow_mut();
+      let connections = match origins.get_mut(origin) {
+         Some(&mut Origin::Http2(ref mut connections)) => connections,
+         Some(&mut Origin::Http1) => return Route::Http1,
+         Some(&mut Origin::Negotiating(ref mut waiting)) => {
+            let (sender, receiver) = oneshot::channel();
+            waiting.push(sender);
+            return Route::Wait(receiver);
+         }
+         None => {
+            origins.insert(origin.to_string(), Origin::Negotiating(vec![]));
+            return Route::Negotiate;
+         }
+      };
+      connections.retain(|connection| connection.borrow().open);
+      let limit = self.streams_per_connection;
+      if let Some(connection) = connections.iter().find(|connection| connection.borrow().streams < limit) {
+         return Route::Http2(StreamSlot::new(connection));
+      }
+      let connection = Http2Connection::opening();
+      connections.push(connection.clone());
+      Route::Open(StreamSlot::new(&connection))
+   }
+
+   fn send_http1(&self, request: Request) -> FutureResponse {
+      Box::new(self.inner.request(request).map(Response::from_http1))
+   }
+
+   /// Makes the first connection to `origin`, offering HTTP/2, and sends `request` over it.
+   fn negotiate(&self, origin: String, request: Request) -> FutureResponse {
+      let client = self.clone();
+      Box::new(self.connector.negotiate(request.uri()).then(move |result| {
+         let waiting = match client.origins.borrow_mut().remove(&origin) {
+            Some(Origin::Negotiating(waiting)) => waiting,
+            _ => vec![]
+         };
+         let response: FutureResponse = match result {
+            Ok(Negotiated::Http2(stream)) => {
+               let connection = Http2Connection::opening();
+               let slot = StreamSlot::new(&connection);
+               client.origins.borrow_mut().insert(origin, Origin::Http2(vec![connection.clone()]));
+               let handshake = client.handshake(stream, connection).map_err(hyper::Error::Io);
+               Box::new(handshake.and_then(move |()| client.send_http2(slot, request)))
+            }
+            Ok(Negotiated::Http1(conn)) => {
+               client.origins.borrow_mut().insert(origin.clone(), Origin::Http1);
+               client.connector.park(origin, conn);
+               client.send_http1(request)
+            }
+            // the waiting requests try to connect themselves
+            Err(f) => Box::new(future::err(hyper::Error::Io(f)))
+         };
+         for waiter in waiting {
+            let _ = waiter.send(());
+         }
+         response
+      }))
+   }
+
+   /// Opens another HTTP/2 connection to `origin` for the request in `slot`.
+   fn open_http2(&self, origin: String, slot: StreamSlot, request: Request) -> FutureResponse {
+      let client = self.clone();
+      Box::new(self.connector.negotiate(request.uri()).then(move |result| -> FutureResponse {
+         let connection = slot.0.clone();
+         match result {
+            Ok(Negotiated::Http2(stream)) => {
+               let handshake = client.handshake(stream, connection).map_err(hyper::Error::Io);
+               Box::new(handshake.and_then(move |()| client.send_http2(slot, request)))
+            }
+            Ok(Negotiated::Http1(conn)) => {
+               // the origin is served by more than one server, and this one only speaks HTTP/1.1
+               Http2Connection::close(&connection);
+               client.connector.park(origin, conn);
+               client.send_http1(request)
+            }
+            Err(f) => {
+               Http2Connection::close(&connection);
+               Box::new(future::err(hyper::Error::Io(f)))
+            }
+         }
+      }))
+   }
+
+   /// Starts HTTP/2 on `stream` for `connection`, and lets the requests waiting for it go ahead.
+   fn handshake(&self,
+                stream: TlsStream<TcpStream>,
+                connection: Rc<RefCell<Http2Connection>>) -> Box<dyn Future<Item = (), Error = io::Error>> {
+      let handle = self.connector.handle.clone();
+      Box::new(h2::client::handshake(stream).then(move |result| match result {
+         Ok((send, driver)) => {
+            let closed = connection.clone();
+            handle.spawn(driver.then(move |_| {
+               Http2Connection::close(&closed);
+               Ok(())
+            }));
+            Http2Connection::ready(&connection, send);
+            Ok(())
+         }
+         Err(f) => {
+            Http2Connection::close(&connection);
+            Err(h2_io_error(f))
+         }
+      }))
+   }
+
+   /// Sends `request` as a stream on the connection of `slot`, once it is ready.  If that
+   /// connection closed or could not be opened, the request is routed again.
+   fn send_http2(&self, slot: StreamSlot, request: Request) -> FutureResponse {
+      let ready = {
+         let mut connection = slot.0.borrow_mut();
+         if !connection.open {
+            None
+         } else if let Some(ref send) = connection.send {
+            Some(Ok(send.clone()))
+         } else {
+            let (sender, receiver) = oneshot::channel();
+            connection.waiting.push(sender);
+            Some(Err(receiver))
+         }
+      };
+      let send = match ready {
+         Some(Ok(send)) => send,
+         Some(Err(handshake)) => {
+            let client = self.clone();
+            return Box::new(handshake.then(move |_| client.send_http2(slot, request)));
+         }
+         None => {
+            drop(slot);
+            return self.execute(request);
+         }
+      };
+
+      let request = match http2_request(&request) {
+         Ok(m) => m,
+         Err(f) => return Box::new(future::err(f))
+      };
+      Box::new(send.ready()
+                   .and_then(move |mut send| send.send_request(request, true))
+                   .and_then(|(response, _)| response)
+                   .map_err(h2_error)
+                   .and_then(move |response| Response::from_http2(response, slot)))
+   }
+}
+
+impl Connector {
+   /// Connects to the origin of `uri`, which has to use HTTPS, offering HTTP/2.
+   fn negotiate(&self, uri: &Uri) -> Connecting<Negotiated> {
+      let url = match Url::parse(uri.as_ref()) {
+         Ok(m) => m,
+         Err(f) => return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, f)))
+      };
+      let tcp = proxy::connect(&self.proxies, &self.dns, &self.handle, &url);
+      let tls = self.tls.clone();
+      Box::new(tcp.and_then(move |stream| tls.negotiate(&url, stream)).map(|(stream, http2)| {
+         if http2 {
+            Negotiated::Http2(stream)
+         } else {
+            Negotiated::Http1(Conn::Tls(stream))
+         }
+      }))
+   }
+
+   /// Keeps `conn` for the next connection hyper asks for to `origin`.
+   fn park(&self, origin: String, conn: Conn) {
+      self.parked.borrow_mut().entry(origin).or_default().push(conn);
+   }
+}
+
+impl Service for Connector {
+   type Request = Uri;
+   type Response = Conn;
+   type Error = io::Error;
+   type Future = Connecting<Conn>;
+
+   fn call(&self, uri: Uri) -> Connecting<Conn> {
+      let url = match Url::parse(uri.as_ref()) {
+         Ok(m) => m,
+         Err(f) => return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, f)))
+      };
+      if let Some(conn) = self.parked.borrow_mut().get_mut(&origin_key(&url)).and_then(Vec::pop) {
+         return Box::new(future::ok(conn));
+      }
+      let tcp = proxy::connect(&self.proxies, &self.dns, &self.handle, &url);
+      if url.scheme() != "https" {
+         return Box::new(tcp.map(Conn::Plain));
+      }
+
+      let tls = self.tls.clone();
+      Box::new(tcp.and_then(move |stream| tls.connect(&url, stream)).map(Conn::Tls))
+   }
+}
+
+impl Response {
+   pub fn status(&self) -> StatusCode {
+      self.status
+   }
+
+   /// The version of HTTP the response was sent over.
+   pub fn version(&self) -> HttpVersion {
+      self.version
+   }
+
+   pub fn headers(&self) -> &Headers {
+      &self.headers
+   }
+
+   pub fn body(self) -> Body {
+      self.body
+   }
+
+   fn from_http1(response: hyper::Response) -> Response {
+      Response {
+         status: response.status(),
+         version: response.version(),
+         headers: response.headers().clone(),
+         body: Body(BodyStream::Http1(response.body()))
+      }
+   }
+
+   fn from_http2(response: http::Response<RecvStream>,
+                 slot: StreamSlot) -> Result<Response, hyper::Error> {
+      let (parts, body) = response.into_parts();
+      let status = match StatusCode::try_from(parts.status.as_u16()) {
+         Ok(m) => m,
+         Err(_) => return Err(hyper::Error::Status)
+      };
+      let mut headers = Headers::new();
+      for (name, value) in &parts.headers {
+         headers.append_raw(name.as_str().to_string(), value.as_bytes().to_vec());
+      }
+      Ok(Response {
+         status,
+         version: HttpVersion::H2,
+         headers,
+         body: Body(BodyStream::Http2 { body, _slot: slot })
+      })
+   }
+}
+
+impl Stream for Body {
+   type Item = Chunk;
+   type Error = hyper::Error;
+
+   fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+      match self.0 {
+         BodyStream::Http1(ref mut body) => body.poll(),
+         BodyStream::Http2 { ref mut body, .. } => match body.poll() {
+            Ok(Async::Ready(Some(data))) => {
+               // let the server send more in place of what was read
+               let _ = body.release_capacity().release_capacity(data.len());
+               Ok(Async::Ready(Some(Chunk::from(data))))
+            }
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(f) => Err(h2_error(f))
+         }
+      }
+   }
+}
+
+impl Http2Connection {
+   fn opening() -> Rc<RefCell<Http2Connection>> {
+      Rc::new(RefCell::new(Http2Connection {
+         send: None,
+         waiting: vec![],
+         streams: 0,
+         open: true
+      }))
+   }
+
+   fn ready(connection: &Rc<RefCell<Http2Connection>>, send: SendRequest<Bytes>) {
+      let waiting = {
+         let mut connection = connection.borrow_mut();
+         connection.send = Some(send);
+         mem::take(&mut connection.waiting)
+      };
+      for waiter in waiting {
+         let _ = waiter.send(());
+      }
+   }
+
+   /// Stops routing requests to `connection` and lets those waiting for it try another one.
+   fn close(connection: &Rc<RefCell<Http2Connection>>) {
+      let waiting = {
+         let mut connection = connection.borrow_mut();
+         connection.open = false;
+         connection.send = None;
+         mem::take(&mut connection.waiting)
+      };
+      for waiter in waiting {
+         let _ = waiter.send(());
+      }
+   }
+}
+
+impl StreamSlot {
+   fn new(connection: &Rc<RefCell<Http2Connection>>) -> StreamSlot {
+      connection.borrow_mut().streams += 1;
+      StreamSlot(connection.clone())
+   }
+}
+
+impl Drop for StreamSlot {
+   fn drop(&mut self) {
+      self.0.borrow_mut().streams -= 1;
+   }
+}
+
+impl Read for Conn {
+   fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      match *self {
+         Conn::Plain(ref mut stream) => stream.read(buf),
+         Conn::Tls(ref mut stream) => stream.read(buf)
+      }
+   }
+}
+
+impl Write for Conn {
+   fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      match *self {
+         Conn::Plain(ref mut stream) => stream.write(buf),
+         Conn::Tls(ref mut stream) => stream.write(buf)
+      }
+   }
+
+   fn flush(&mut self) -> io::Result<()> {
+      match *self {
+         Conn::Plain(ref mut stream) => stream.flush(),
+         Conn::Tls(ref mut stream) => stream.flush()
+      }
+   }
+}
+
+impl AsyncRead for Conn {}
+
+impl AsyncWrite for Conn {
+   fn shutdown(&mut self) -> Poll<(), io::Error> {
+      match *self {
+         Conn::Plain(ref mut stream) => AsyncWrite::shutdown(stream),
+         Conn::Tls(ref mut stream) => stream.shutdown()
+      }
+   }
+}
+
+/// Identifies the server `url` is served by, e.g. `https://example.com:443`.
+fn origin_key(url: &Url) -> String {
+   format!("{}://{}:{}",
+           url.scheme(),
+           url.host_str().unwrap_or(""),
+           url.port_or_known_default().unwrap_or(0))
+}
+
+/// Converts `request` for sending as an HTTP/2 stream.
+fn http2_request(request: &Request) -> Result<http::Request<()>, hyper::Error> {
+   let mut builder = http::Request::builder();
+   builder.method(request.method().as_ref()).uri(request.uri().as_ref());
+   for header in request.headers().iter() {
+      if CONNECTION_HEADERS.contains(&&header.name().to_ascii_lowercase()[..]) {
+         continue;
+      }
+      for value in header.raw() {
+         builder.header(header.name(), value);
+      }
+   }
+   match builder.body(()) {
+      Ok(m) => Ok(m),
+      Err(f) => Err(hyper::Error::Io(io::Error::new(io::ErrorKind::InvalidInput, f)))
+   }
+}
+
+fn h2_io_error(err: h2::Error) -> io::Error {
+   if err.is_io() {
+      err.into_io().unwrap_or_else(|| io::Error::other("connection failed"))
+   } else {
+      io::Error::other(err)
+   }
+}
+
+fn h2_error(err: h2::Error) -> hyper::Error {
+   hyper::Error::Io(h2_io_error(err))
+}
@@ -0,0 +1,350 @@
+// This file is part of rget.
+//
+// Copyright (C) 2016-2017 Arcterus (Alex Lyon) and rget contributors.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::error::Error as StdError;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+
+use brotli_decompressor::DecompressorWriter;
+use flate2::{Decompress, FlushDecompress, Status};
+use flate2::write::GzDecoder;
+use hyper::header::{AcceptEncoding, ContentEncoding, Encoding, Headers, qitem};
+use zstd::stream::{raw, zio};
+
+use error::{self, Error, ErrorReason};
+
+const BROTLI_BUFFER_SIZE: usize = 8192;
+const ZLIB_BUFFER_SIZE: usize = 32 * 1024;
+
+/// The `Accept-Encoding` header to send.  Unless a compressed transfer is wanted, this asks for
+/// the file as is, as byte ranges of an encoded response do not line up with the file.
+pub fn accept_encoding(compressed: bool) -> AcceptEncoding {
+   if compressed {
+      AcceptEncoding(vec![qitem(Encoding::Gzip),
+                          qitem(Encoding::Deflate),
+                          qitem(Encoding::Brotli),
+                          qitem(Encoding::EncodingExt("zstd".to_string()))])
+   } else {
+      AcceptEncoding(vec![qitem(Encoding::Identity)])
+   }
+}
+
+/// The content codings applied to a response, in the order they were applied.
+pub fn content_encoding(headers: &Headers) -> Vec<Encoding> {
+   match headers.get() {
+      Some(ContentEncoding(encodings)) => {
+         encodings.iter().filter(|&encoding| *encoding != Encoding::Identity).cloned().collect()
+      }
+      None => vec![]
+   }
+}
+
+/// A writer that decodes the data written to it.
+pub trait Decoder: Write {
+   /// Writes out the rest of the decoded data once everything has been written, failing if the
+   /// encoded data was cut short or is corrupt.
+   fn finish(&mut self) -> io::Result<()>;
+}
+
+/// The innermost writer, which receives the decoded data.  Its errors are marked as
+/// `OutputError`s so that they can be told apart from the encoded data being corrupt.
+struct Plain<W: Write>(W);
+
+/// An error of the writer the decoded data goes to.
+#[derive(Debug)]
+struct OutputError(io::Error);
+
+impl<W: Write> Write for Plain<W> {
+   fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.write(buf).map_err(OutputError::wrap)
+   }
+
+   fn flush(&mut self) -> io::Result<()> {
+      self.0.flush().map_err(OutputError::wrap)
+   }
+}
+
+impl<W: Write> Decoder for Plain<W> {
+   fn finish(&mut self) -> io::Result<()> {
+      self.0.flush().map_err(OutputError::wrap)
+   }
+}
+
+impl OutputError {
+   fn wrap(error: io::Error) -> io::Error {
+      io::Error::new(error.kind(), OutputError(error))
+   }
+}
+
+impl Display for OutputError {
+   fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+      self.0.fmt(fmt)
+   }
+}
+
+impl StdError for OutputError {
+   fn source(&self) -> Option<&(dyn StdError + 'static)> {
+      Some(&self.0)
+   }
+}
+
+impl<D: Decoder + ?Sized> Decoder for Box<D> {
+   fn finish(&mut self) -> io::Result<()> {
+      (**self).finish()
+   }
+}
+
+impl<D: Decoder> Decoder for GzDecoder<D> {
+   fn finish(&mut self) -> io::Result<()> {
+      self.try_finish()?;
+      self.get_mut().finish()
+   }
+}
+
+/// Decodes zlib data.  Unlike `flate2::write::ZlibDecoder`, this knows whether the stream was
+/// complete, which is only the case once its checksum has been read and matched.
+struct ZlibDecoder<D: Decoder> {
+   output: D,
+   state: Decompress,
+   buffer: Vec<u8>,
+   ended: bool
+}
+
+impl<D: Decoder> ZlibDecoder<D> {
+   fn new(output: D) -> ZlibDecoder<D> {
+      ZlibDecoder {
+         output,
+         state: Decompress::new(true),
+         buffer: Vec::with_capacity(ZLIB_BUFFER_SIZE),
+         ended: false
+      }
+   }
+}
+
+impl<D: Decoder> Write for ZlibDecoder<D> {
+   fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      let start = self.state.total_in();
+      loop {
+         let consumed = (self.state.total_in() - start) as usize;
+         if self.ended {
+            if consumed < buf.len() {
+               return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                         "data after the end of the deflate stream"));
+            }
+            return Ok(consumed);
+         }
+         self.buffer.clear();
+         let status = match self.state.decompress_vec(&buf[consumed..],
+                                                      &mut self.buffer,
+                                                      FlushDecompress::None) {
+            Ok(m) => m,
+            Err(f) => return Err(io::Error::new(io::ErrorKind::InvalidData, f))
+         };
+         self.output.write_all(&self.buffer)?;
+         self.ended = status == Status::StreamEnd;
+         // stop once the input is used up, unless more output may be waiting
+         let done = (self.state.total_in() - start) as usize == buf.len();
+         if !self.ended && done && self.buffer.len() < self.buffer.capacity() {
+            return Ok(buf.len());
+         }
+         if status == Status::BufError {
+            return Ok((self.state.total_in() - start) as usize);
+         }
+      }
+   }
+
+   fn flush(&mut self) -> io::Result<()> {
+      self.output.flush()
+   }
+}
+
+impl<D: Decoder> Decoder for ZlibDecoder<D> {
+   fn finish(&mut self) -> io::Result<()> {
+      if !self.ended {
+         return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete deflate stream"));
+      }
+      self.output.finish()
+   }
+}
+
+impl<D: Decoder> Decoder for DecompressorWriter<D> {
+   fn finish(&mut self) -> io::Result<()> {
+      self.close()?;
+      self.get_mut().finish()
+   }
+}
+
+impl<D: Decoder> Decoder for zio::Writer<D, raw::Decoder> {
+   fn finish(&mut self) -> io::Result<()> {
+      zio::Writer::finish(self)?;
+      self.writer_mut().finish()
+   }
+}
+
+/// Turns an error of a `Decoder` into an `ErrorReason::IO` if writing the decoded data failed,
+/// or into an `ErrorReason::Decoding` if the encoded data could not be decoded.
+pub fn decoder_error(error: io::Error) -> Error {
+   if !error.get_ref().is_some_and(|inner| inner.is::<OutputError>()) {
+      return Error::new(ErrorReason::Decoding(error));
+   }
+   match error.into_inner().map(|inner| inner.downcast::<OutputError>()) {
+      Some(Ok(inner)) => Error::new(ErrorReason::IO(inner.0)),
+      _ => unreachable!()
+   }
+}
+
+/// Wraps `output` so that data written to it is decoded from `encodings` first.
+pub fn decoder<'a, W: Write + 'a>(encodings: &[Encoding],
+                                  output: W) -> error::Result<Box<dyn Decoder + 'a>> {
+   let mut output: Box<dyn Decoder + 'a> = Box::new(Plain(output));
+   // the last coding applied is the first one that has to be undone, so wrap the innermost
+   // writer first
+   for encoding in encodings {
+      output = match *encoding {
+         Encoding::Gzip => Box::new(GzDecoder::new(output)),
+         Encoding::Deflate => Box::new(ZlibDecoder::new(output)),
+         Encoding::Brotli => Box::new(DecompressorWriter::new(output, BROTLI_BUFFER_SIZE)),
+         Encoding::EncodingExt(ref name) if name == "zstd" => {
+            match raw::Decoder::new() {
+               Ok(m) => Box::new(zio::Writer::new(output, m)),
+               Err(f) => return Err(Error::new(ErrorReason::IO(f)))
+            }
+         }
+         ref other => return Err(Error::new(ErrorReason::UnsupportedEncoding(other.to_string())))
+      };
+   }
+   Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+   use std::io::{self, Write};
+
+   use brotli::CompressorWriter;
+   use flate2::Compression;
+   use flate2::write::{GzEncoder, ZlibEncoder};
+   use hyper::header::Encoding;
+   use zstd;
+
+   use error::ErrorKind;
+   use super::*;
+
+   fn content() -> Vec<u8> {
+      (0..100_000u32).map(|i| (i * 7919 % 251) as u8).chain(b"the end".iter().cloned()).collect()
+   }
+
+   fn encode(encoding: &Encoding, data: &[u8]) -> Vec<u8> {
+      match *encoding {
+         Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+         }
+         Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+         }
+         Encoding::Brotli => {
+            let mut encoder = CompressorWriter::new(vec![], 4096, 5, 22);
+            encoder.write_all(data).unwrap();
+            encoder.into_inner()
+         }
+         _ => zstd::encode_all(data, 3).unwrap()
+      }
+   }
+
+   fn encodings() -> Vec<Encoding> {
+      vec![Encoding::Gzip,
+           Encoding::Deflate,
+           Encoding::Brotli,
+           Encoding::EncodingExt("zstd".to_string())]
+   }
+
+   /// Writes `encoded` to a decoder for `encoding` in chunks of `chunk_size` bytes.
+   fn decode(encoding: &Encoding, encoded: &[u8], chunk_size: usize) -> io::Result<Vec<u8>> {
+      let mut output = vec![];
+      {
+         let mut decoder = decoder(&[encoding.clone()], &mut output).unwrap();
+         for chunk in encoded.chunks(chunk_size) {
+            decoder.write_all(chunk)?;
+         }
+         decoder.finish()?;
+      }
+      Ok(output)
+   }
+
+   #[test]
+   fn decodes_whole_content() {
+      let data = content();
+      for encoding in encodings() {
+         let encoded = encode(&encoding, &data);
+         assert!(decode(&encoding, &encoded, encoded.len()).unwrap() == data, "{}", encoding);
+      }
+   }
+
+   #[test]
+   fn decodes_content_in_chunks() {
+      let data = content();
+      for encoding in encodings() {
+         let encoded = encode(&encoding, &data);
+         for &chunk_size in &[1, 7, 4096] {
+            assert!(decode(&encoding, &encoded, chunk_size).unwrap() == data, "{}", encoding);
+         }
+      }
+   }
+
+   #[test]
+   fn rejects_truncated_content() {
+      let data = content();
+      for encoding in encodings() {
+         let encoded = encode(&encoding, &data);
+         for &cut in &[1, 5, encoded.len() / 2] {
+            let truncated = &encoded[..encoded.len() - cut];
+            assert!(decode(&encoding, truncated, 1024).is_err(), "{} cut by {}", encoding, cut);
+         }
+      }
+   }
+
+   #[test]
+   fn rejects_trailing_garbage() {
+      let data = content();
+      for encoding in encodings() {
+         let mut encoded = encode(&encoding, &data);
+         encoded.extend_from_slice(b"garbage");
+         assert!(decode(&encoding, &encoded, encoded.len()).is_err(), "{}", encoding);
+         assert!(decode(&encoding, &encoded, 3).is_err(), "{}", encoding);
+      }
+   }
+
+   #[test]
+   fn tells_corrupt_content_from_output_errors() {
+      struct Full;
+
+      impl Write for Full {
+         fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+         }
+
+         fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+         }
+      }
+
+      let data = content();
+      for encoding in encodings() {
+         let encoded = encode(&encoding, &data);
+         let mut decoder = decoder(&[encoding.clone()], Full).unwrap();
+         let err = decoder.write_all(&encoded).and_then(|()| decoder.finish()).unwrap_err();
+         assert_eq!(decoder_error(err).kind(), ErrorKind::Io, "{}", encoding);
+
+         let err = decode(&encoding, &encoded[..encoded.len() - 1], 1024).unwrap_err();
+         assert_eq!(decoder_error(err).kind(), ErrorKind::Encoding, "{}", encoding);
+      }
+   }
+}
@@ -0,0 +1,510 @@
+// This file is part of rget.
+//
+// Copyright (C) 2016-2017 Arcterus (Alex Lyon) and rget contributors.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll, Stream};
+use futures::stream::FuturesUnordered;
+use hyper::StatusCode;
+use tokio_core::reactor::{Handle, Timeout};
+
+use error::{Error, ErrorReason};
+use report::PartReport;
+
+/// The number of connections an adaptive download starts with.
+const ADAPTIVE_START: usize = 2;
+/// How long the throughput is measured for before deciding whether to change the number of
+/// connections.
+const ADAPTIVE_WINDOW: u64 = 2;
+/// Another connection is only added if the last one made the download at least this much faster.
+const MIN_IMPROVEMENT: f64 = 1.1;
+/// The speed of a connection has collapsed once it drops below this fraction of the best seen.
+const COLLAPSE: f64 = 0.5;
+/// How often a part is requested again after the server turned it away before it fails.
+const MAX_BUSY_RETRIES: u32 = 5;
+/// The longest a server may ask to wait before a part is requested again, in seconds.  A part
+/// the server wants to keep away for longer fails instead.
+const MAX_RETRY_AFTER: u64 = 5 * 60;
+
+/// Tracks the combined throughput of all parts to find its peak.
+pub struct Throughput {
+   window_start: Instant,
+   window_bytes: u64,
+   peak: f64,
+   total: u64
+}
+
+/// Decides how many parts of a download are downloaded at the same time.
+///
+/// A fixed download runs all of its parts at once.  An adaptive one starts with a few and adds
+/// another whenever the last one made the download faster, until it stops helping.  Either runs
+/// fewer once the server pushes back (429 or 503), and an adaptive one also does when the speed
+/// of each connection collapses.
+pub struct Concurrency {
+   limit: usize,
+   max: usize,
+   adaptive: bool,
+   growing: bool,
+   /// Whether the server pushed back, so that `limit` is the most connections it accepts.
+   pushed_back: bool,
+   /// When the current window started, once the first sample was taken.
+   window_start: Option<Instant>,
+   window_total: u64,
+   /// The throughput of the previous window.
+   previous: Option<f64>,
+   /// The best throughput of a single connection so far.
+   best_per_connection: f64
+}
+
+/// A running part of a download.
+pub type Part<'a> = Box<dyn Future<Item = PartReport, Error = Error> + 'a>;
+/// Starts a part of a download, which sends its request.  A part the server turned away is
+/// started again.
+pub type StartPart<'a> = Rc<dyn Fn() -> Part<'a> + 'a>;
+type Running<'a> = Box<dyn Future<Item = (usize, Result<PartReport, Error>), Error = ()> + 'a>;
+
+/// Something the `Scheduler` did that the rest of the download should know about.
+pub enum ScheduleEvent {
+   /// The number of connections changed, for the reason given.
+   Changed(String),
+   /// The server turned part `part` away, so it is requested again after at least `delay`.
+   Retry { part: usize, delay: Duration },
+   /// The server turned part `part` away too often or for too long, so it failed.
+   GaveUp { part: usize }
+}
+
+/// Runs the parts of a download, starting each once `Concurrency` allows another connection.
+/// Parts are started in order and all of them run to completion even if one fails.
+///
+/// A part the server turns away with 429 or 503 goes back into the queue ahead of the parts after
+/// it, to be requested again on a free connection once the delay the server asked for has passed.
+/// It is requested again as a whole: its range is not split up between the other connections, as
+/// the state of a download only records parts of the size they were planned with.
+pub struct Scheduler<'a> {
+   parts: Vec<StartPart<'a>>,
+   /// The parts waiting for a connection along with when they may be requested.
+   pending: VecDeque<(usize, Option<Instant>)>,
+   running: FuturesUnordered<Running<'a>>,
+   results: Vec<Option<Result<PartReport, Error>>>,
+   retries: Vec<u32>,
+   concurrency: Concurrency,
+   meter: Rc<RefCell<Throughput>>,
+   handle: Handle,
+   events: Box<dyn Fn(ScheduleEvent) + 'a>
+}
+
+impl Throughput {
+   pub fn new() -> Throughput {
+      Throughput {
+         window_start: Instant::now(),
+         window_bytes: 0,
+         peak: 0.0,
+         total: 0
+      }
+   }
+
+   pub fn add(&mut self, bytes: u64) {
+      self.total += bytes;
+      self.window_bytes += bytes;
+      let elapsed = self.window_start.elapsed();
+      if elapsed >= Duration::from_secs(1) {
+         self.peak = self.peak.max(self.window_bytes as f64 / elapsed.as_secs_f64());
+         self.window_start = Instant::now();
+         self.window_bytes = 0;
+      }
+   }
+
+   /// The peak throughput, or `average` if the download did not even take a second.
+   pub fn peak(&self, average: f64) -> f64 {
+      if self.peak > 0.0 { self.peak } else { average }
+   }
+
+   /// The bytes received by all parts so far.
+   pub fn total(&self) -> u64 {
+      self.total
+   }
+}
+
+impl Default for Throughput {
+   fn default() -> Throughput {
+      Throughput::new()
+   }
+}
+
+impl Concurrency {
+   /// Runs all `parts` at once.
+   pub fn fixed(parts: usize) -> Concurrency {
+      Concurrency::new(parts, parts, false)
+   }
+
+   /// Runs between one and `parts` parts at once depending on how fast the download is.
+   pub fn adaptive(parts: usize) -> Concurrency {
+      Concurrency::new(ADAPTIVE_START.min(parts), parts, true)
+   }
+
+   fn new(limit: usize, max: usize, adaptive: bool) -> Concurrency {
+      Concurrency {
+         limit: limit.max(1),
+         max: max.max(1),
+         adaptive,
+         growing: adaptive,
+         pushed_back: false,
+         window_start: None,
+         window_total: 0,
+         previous: None,
+         best_per_connection: 0.0
+      }
+   }
+
+   /// The number of parts that may run at the same time.
+   pub fn limit(&self) -> usize {
+      self.limit
+   }
+
+   /// Never runs more than `limit` parts at once, as the server pushed back at that many before.
+   pub fn cap(&mut self, limit: usize) {
+      self.limit = self.limit.min(limit).max(1);
+      self.max = self.max.min(limit).max(1);
+      self.pushed_back = true;
+   }
+
+   /// The most connections the server accepts, if it pushed back.
+   pub fn accepted(&self) -> Option<usize> {
+      if self.pushed_back { Some(self.limit) } else { None }
+   }
+
+   /// Records that the parts have received `total` bytes by `now`, `running` of them being
+   /// connected.  Once a window has passed, returns a description of any change made.
+   pub fn sample(&mut self, total: u64, running: usize, now: Instant) -> Option<String> {
+      let window_start = *self.window_start.get_or_insert(now);
+      let elapsed = now.saturating_duration_since(window_start);
+      if !self.adaptive || elapsed < Duration::from_secs(ADAPTIVE_WINDOW) {
+         return None;
+      }
+      let throughput = (total - self.window_total) as f64 / elapsed.as_secs_f64();
+      self.window_start = Some(now);
+      self.window_total = total;
+      if throughput <= 0.0 || running == 0 {
+         return None;
+      }
+
+      let per_connection = throughput / running as f64;
+      self.best_per_connection = self.best_per_connection.max(per_connection);
+      let previous = self.previous.replace(throughput);
+      if running > 1 && per_connection < self.best_per_connection * COLLAPSE {
+         self.growing = false;
+         self.limit = running - 1;
+         return Some(format!("speed per connection collapsed to {:.0} B/s, using at most {} \
+                              connections",
+                             per_connection,
+                             self.limit));
+      }
+      // only a window in which every allowed connection was busy says anything about adding more
+      if !self.growing || running < self.limit || self.limit >= self.max {
+         return None;
+      }
+      match previous {
+         Some(previous) if throughput < previous * MIN_IMPROVEMENT => {
+            self.growing = false;
+            Some(format!("throughput stopped improving at {:.0} B/s, staying at {} connections",
+                         throughput,
+                         self.limit))
+         }
+         _ => {
+            self.limit += 1;
+            Some(format!("throughput is {:.0} B/s, adding connection {}", throughput, self.limit))
+         }
+      }
+   }
+
+   /// Runs fewer parts at once than the `running` ones because the server pushed back.
+   pub fn back_off(&mut self, running: usize) -> Option<String> {
+      self.pushed_back = true;
+      if self.limit == 1 {
+         return None;
+      }
+      self.growing = false;
+      self.limit = running.saturating_sub(1).clamp(1, self.limit);
+      Some(format!("server is overloaded, using at most {} connections", self.limit))
+   }
+}
+
+impl<'a> Scheduler<'a> {
+   /// Creates a scheduler for `parts`, which measures their speed with `meter`, waits for the
+   /// server on the event loop of `handle` and reports what it does to `events`.
+   pub fn new(parts: Vec<StartPart<'a>>,
+              concurrency: Concurrency,
+              meter: Rc<RefCell<Throughput>>,
+              handle: Handle,
+              events: Box<dyn Fn(ScheduleEvent) + 'a>) -> Scheduler<'a> {
+      Scheduler {
+         pending: (0..parts.len()).map(|index| (index, None)).collect(),
+         results: parts.iter().map(|_| None).collect(),
+         retries: parts.iter().map(|_| 0).collect(),
+         parts,
+         running: FuturesUnordered::new(),
+         concurrency,
+         meter,
+         handle,
+         events
+      }
+   }
+
+   pub fn concurrency(&self) -> &Concurrency {
+      &self.concurrency
+   }
+
+   /// The number of times parts were requested again.
+   pub fn retries(&self) -> u64 {
+      self.retries.iter().map(|&retries| retries as u64).sum()
+   }
+
+   fn start_parts(&mut self) {
+      while self.running.len() < self.concurrency.limit() {
+         let (index, not_before) = match self.pending.pop_front() {
+            Some(m) => m,
+            None => break
+         };
+         let start = self.parts[index].clone();
+         let wait = not_before.map_or(Duration::from_secs(0), |not_before| {
+            not_before.saturating_duration_since(Instant::now())
+         });
+         let part = if wait > Duration::from_secs(0) {
+            match Timeout::new(wait, &self.handle) {
+               Ok(timeout) => Box::new(timeout.then(move |_| start())) as Part<'a>,
+               // without a timer the request is just sent right away
+               Err(_) => start()
+            }
+         } else {
+            start()
+         };
+         self.running.push(Box::new(part.then(move |result| Ok((index, result)))));
+      }
+   }
+
+   /// Queues part `index` to be requested again if the server turned it away, it has not been
+   /// retried too often yet and the server did not ask to wait too long.  Returns the result
+   /// otherwise.
+   fn retry(&mut self,
+            index: usize,
+            result: Result<PartReport, Error>) -> Option<Result<PartReport, Error>> {
+      let asked = match result {
+         Err(ref f) => match *f.reason() {
+            ErrorReason::ServerBusy(_, delay) => delay,
+            _ => return Some(result)
+         },
+         Ok(_) => return Some(result)
+      };
+      if let Some(change) = self.concurrency.back_off(self.running.len() + 1) {
+         (self.events)(ScheduleEvent::Changed(change));
+      }
+      let too_long = asked.is_some_and(|delay| delay > Duration::from_secs(MAX_RETRY_AFTER));
+      if self.retries[index] == MAX_BUSY_RETRIES || too_long {
+         (self.events)(ScheduleEvent::GaveUp { part: index });
+         return Some(result);
+      }
+      // wait twice as long every time unless the server said how long
+      let delay = asked.unwrap_or_else(|| Duration::from_secs(1 << self.retries[index]));
+      self.retries[index] += 1;
+      (self.events)(ScheduleEvent::Retry { part: index, delay });
+      // the queue stays in order as, when writing to standard output, later parts wait for the
+      // earlier ones
+      let at = self.pending.iter().position(|&(other, _)| other > index).unwrap_or(self.pending.len());
+      self.pending.insert(at, (index, Some(Instant::now() + delay)));
+      None
+   }
+}
+
+impl<'a> Future for Scheduler<'a> {
+   type Item = Vec<Result<PartReport, Error>>;
+   type Error = ();
+
+   fn poll(&mut self) -> Poll<Self::Item, ()> {
+      loop {
+         self.start_parts();
+         let limit = self.concurrency.limit();
+         match self.running.poll()? {
+            Async::Ready(Some((index, result))) => {
+               if let Some(result) = self.retry(index, result) {
+                  self.results[index] = Some(result);
+               }
+            }
+            Async::Ready(None) => {
+               if self.pending.is_empty() {
+                  let results = self.results.drain(..).map(|result| match result {
+                     Some(result) => result,
+                     None => unreachable!()
+                  });
+                  return Ok(Async::Ready(results.collect()));
+               }
+            }
+            Async::NotReady => {
+               let total = self.meter.borrow().total();
+               let running = self.running.len();
+               if let Some(change) = self.concurrency.sample(total, running, Instant::now()) {
+                  (self.events)(ScheduleEvent::Changed(change));
+               }
+               // parts are only polled once they are started, so start them right away
+               if self.concurrency.limit() <= limit || self.pending.is_empty() {
+                  return Ok(Async::NotReady);
+               }
+            }
+         }
+      }
+   }
+}
+
+/// Whether the server answered with `status` because it has too many requests to deal with.
+pub fn is_back_pressure(status: StatusCode) -> bool {
+   status == StatusCode::TooManyRequests || status == StatusCode::ServiceUnavailable
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   /// Feeds windows of measured throughput to a `Concurrency` without waiting for them.
+   struct Windows {
+      concurrency: Concurrency,
+      now: Instant,
+      total: u64
+   }
+
+   impl Windows {
+      fn new(concurrency: Concurrency) -> Windows {
+         let mut windows = Windows {
+            concurrency,
+            now: Instant::now(),
+            total: 0
+         };
+         assert_eq!(windows.concurrency.sample(0, 0, windows.now), None);
+         windows
+      }
+
+      /// A window in which `running` parts received `throughput` bytes per second in total.
+      fn pass(&mut self, throughput: u64, running: usize) -> Option<String> {
+         self.now += Duration::from_secs(ADAPTIVE_WINDOW);
+         self.total += throughput * ADAPTIVE_WINDOW;
+         self.concurrency.sample(self.total, running, self.now)
+      }
+
+      fn limit(&self) -> usize {
+         self.concurrency.limit()
+      }
+   }
+
+   #[test]
+   fn fixed_runs_every_part() {
+      let mut windows = Windows::new(Concurrency::fixed(4));
+      assert_eq!(windows.limit(), 4);
+      assert_eq!(windows.pass(100, 4), None);
+      assert_eq!(windows.pass(10, 4), None);
+      assert_eq!(windows.limit(), 4);
+   }
+
+   #[test]
+   fn adaptive_starts_with_a_few_parts() {
+      assert_eq!(Concurrency::adaptive(8).limit(), ADAPTIVE_START);
+      assert_eq!(Concurrency::adaptive(1).limit(), 1);
+   }
+
+   #[test]
+   fn waits_for_the_window_to_pass() {
+      let mut concurrency = Concurrency::adaptive(8);
+      let start = Instant::now();
+      assert_eq!(concurrency.sample(0, 2, start), None);
+      assert_eq!(concurrency.sample(1000, 2, start + Duration::from_secs(1)), None);
+      assert_eq!(concurrency.limit(), 2);
+   }
+
+   #[test]
+   fn grows_while_the_download_gets_faster() {
+      let mut windows = Windows::new(Concurrency::adaptive(4));
+      assert!(windows.pass(100, 2).is_some());
+      assert_eq!(windows.limit(), 3);
+      assert!(windows.pass(150, 3).is_some());
+      assert_eq!(windows.limit(), 4);
+      // every part is running
+      assert_eq!(windows.pass(200, 4), None);
+      assert_eq!(windows.limit(), 4);
+   }
+
+   #[test]
+   fn stays_once_the_download_stops_getting_faster() {
+      let mut windows = Windows::new(Concurrency::adaptive(8));
+      windows.pass(100, 2);
+      assert_eq!(windows.limit(), 3);
+      assert!(windows.pass(105, 3).unwrap().contains("stopped improving"));
+      assert_eq!(windows.limit(), 3);
+      assert_eq!(windows.pass(200, 3), None);
+      assert_eq!(windows.limit(), 3);
+   }
+
+   #[test]
+   fn ignores_windows_with_free_connections() {
+      let mut windows = Windows::new(Concurrency::adaptive(8));
+      windows.pass(100, 2);
+      assert_eq!(windows.limit(), 3);
+      assert_eq!(windows.pass(100, 2), None);
+      assert_eq!(windows.limit(), 3);
+   }
+
+   #[test]
+   fn drops_a_connection_when_their_speed_collapses() {
+      let mut windows = Windows::new(Concurrency::adaptive(8));
+      windows.pass(100, 2);
+      assert_eq!(windows.limit(), 3);
+      assert!(windows.pass(60, 3).unwrap().contains("collapsed"));
+      assert_eq!(windows.limit(), 2);
+      // and does not grow again
+      assert_eq!(windows.pass(1000, 2), None);
+      assert_eq!(windows.limit(), 2);
+   }
+
+   #[test]
+   fn backs_off_when_the_server_pushes_back() {
+      let mut concurrency = Concurrency::fixed(4);
+      assert!(concurrency.back_off(4).is_some());
+      assert_eq!(concurrency.limit(), 3);
+      assert!(concurrency.back_off(1).is_some());
+      assert_eq!(concurrency.limit(), 1);
+      assert_eq!(concurrency.back_off(1), None);
+
+      let mut windows = Windows::new(Concurrency::adaptive(8));
+      windows.concurrency.back_off(2);
+      assert_eq!(windows.limit(), 1);
+      assert_eq!(windows.pass(100, 1), None);
+      assert_eq!(windows.limit(), 1);
+   }
+
+   #[test]
+   fn remembers_how_many_connections_the_server_accepts() {
+      let mut concurrency = Concurrency::fixed(4);
+      assert_eq!(concurrency.accepted(), None);
+      concurrency.back_off(3);
+      assert_eq!(concurrency.accepted(), Some(2));
+
+      let mut concurrency = Concurrency::fixed(4);
+      concurrency.cap(2);
+      assert_eq!(concurrency.limit(), 2);
+      assert_eq!(concurrency.accepted(), Some(2));
+   }
+
+   #[test]
+   fn does_not_grow_past_a_cap() {
+      let mut windows = Windows::new(Concurrency::adaptive(8));
+      windows.concurrency.cap(3);
+      windows.pass(100, 2);
+      assert_eq!(windows.limit(), 3);
+      assert_eq!(windows.pass(200, 3), None);
+      assert_eq!(windows.limit(), 3);
+   }
+}
@@ -6,11 +6,20 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::error;
 use std::fmt::{self, Display, Formatter};
-use reqwest::{self, StatusCode};
+use hyper::{self, StatusCode};
 use std::io;
+use std::path::{Path, PathBuf};
+use std::slice;
+use native_tls;
 use toml;
 use std::any::Any;
+use std::time::Duration;
+use url::{self, Url};
+
+use proxy::TunnelRefused;
+use tls;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -19,37 +28,285 @@ pub enum ErrorReason {
    IO(io::Error),
    MissingUrl,
    HttpErrorCode(StatusCode),
-   FailedRequest(reqwest::Error),
+   /// The server has too many requests to deal with (429 or 503), along with how long it asked
+   /// to wait before trying again.
+   ServerBusy(StatusCode, Option<Duration>),
+   FailedRequest(hyper::Error),
+   /// The proxy answered with an error status instead of opening a tunnel to the server.
+   ProxyErrorCode(StatusCode),
    InvalidConfig(&'static str),
    InvalidToml(toml::de::Error),
-   InvalidUrl(reqwest::UrlError),
-   FailedThread(Box<Any + Send + 'static>),
+   InvalidUrl(url::ParseError),
+   InvalidProxy(String),
+   InvalidCertificate(PathBuf),
+   Tls(native_tls::Error),
+   TooManyRedirects(usize),
+   ForbiddenRedirect(Url),
+   FileExists(PathBuf),
+   /// The directory, the bytes the download needs and the bytes available there.
+   NotEnoughSpace(PathBuf, u64, u64),
+   UnsupportedEncoding(String),
+   UnexpectedEncoding(String),
+   /// The content the server sent could not be decoded, e.g. because it was cut short.
+   Decoding(io::Error),
+   FailedThread(Box<dyn Any + Send + 'static>),
    Multiple(Vec<Error>)
 }
 
+/// The broad class of an `Error`, for telling e.g. a missing file on the server apart from a
+/// full disk without matching every `ErrorReason`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+   /// Reading or writing a local file failed.
+   Io,
+   /// The options or the saved state of a download are invalid.
+   Config,
+   /// A URL could not be parsed.
+   InvalidUrl,
+   /// The server answered with an error status, see `Error::status()`.
+   HttpStatus,
+   /// A request could not be sent or its response could not be received.
+   Network,
+   /// A TLS connection could not be set up, or the server's certificate or key was rejected.
+   Tls,
+   /// A redirect was not followed.
+   Redirect,
+   /// The output file already exists.
+   FileExists,
+   /// The server sent content rget cannot decode or did not ask for.
+   Encoding,
+   /// A thread of rget itself failed.
+   Internal,
+   /// Several parts failed, see `Error::errors()`.
+   Multiple
+}
+
+/// The status the `rget` binary exits with, modelled on wget's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitCode {
+   Success = 0,
+   /// An error that does not fit any of the other codes.
+   Generic = 1,
+   /// The command line, a URL or the saved state of a download could not be parsed.
+   Parse = 2,
+   /// Reading or writing a local file failed.
+   Io = 3,
+   /// A request could not be sent or its response could not be received.
+   Network = 4,
+   /// A TLS connection could not be set up, or the server's certificate or key was rejected.
+   Tls = 5,
+   /// The server or the proxy asked for credentials (401 or 407).
+   Auth = 6,
+   /// The server answered with an error status or with a response rget cannot use.
+   Server = 8,
+   /// The downloaded file does not match its checksum.
+   ChecksumMismatch = 9,
+   /// rget was interrupted, e.g. by Ctrl-C (128 + SIGINT).
+   Interrupted = 130
+}
+
+/// An I/O error on a file along with what was being done to it, e.g. "could not create
+/// foo.part0: No space left on device".
+///
+/// It is passed around inside an `io::Error` of the same `io::ErrorKind` as the original error,
+/// so a full disk can still be told apart from e.g. a permission error.
+#[derive(Debug)]
+pub struct FileError {
+   action: &'static str,
+   path: PathBuf,
+   error: io::Error
+}
+
 #[derive(Debug)]
 pub struct Error {
-   reason: ErrorReason
+   reason: Box<ErrorReason>,
+   /// The part of the download that failed, along with the URL it was downloaded from.
+   part: Option<Box<(u64, Url)>>
 }
 
 impl Error {
    pub fn new(reason: ErrorReason) -> Error {
       Error {
-         reason: reason
+         reason: Box::new(reason),
+         part: None
+      }
+   }
+
+   /// Wraps the error of a request that could not be sent or answered.  A proxy that refused to
+   /// open a tunnel is reported with the status it answered with.
+   pub fn failed_request(err: hyper::Error) -> Error {
+      let refused = match err {
+         hyper::Error::Io(ref err) => {
+            err.get_ref().and_then(|err| err.downcast_ref::<TunnelRefused>()).map(TunnelRefused::status)
+         }
+         _ => None
+      };
+      match refused {
+         Some(status) => Error::new(ErrorReason::ProxyErrorCode(status)),
+         None => Error::new(ErrorReason::FailedRequest(err))
+      }
+   }
+
+   /// Records that the error happened while downloading part `part` from `url`.
+   pub fn in_part(mut self, part: u64, url: Url) -> Error {
+      self.part = Some(Box::new((part, url)));
+      self
+   }
+
+   pub fn reason(&self) -> &ErrorReason {
+      &self.reason
+   }
+
+   pub fn kind(&self) -> ErrorKind {
+      match *self.reason {
+         ErrorReason::IO(_) | ErrorReason::NotEnoughSpace(..) => ErrorKind::Io,
+         ErrorReason::MissingUrl
+            | ErrorReason::InvalidConfig(_)
+            | ErrorReason::InvalidToml(_)
+            | ErrorReason::InvalidProxy(_)
+            | ErrorReason::InvalidCertificate(_) => ErrorKind::Config,
+         ErrorReason::InvalidUrl(_) => ErrorKind::InvalidUrl,
+         ErrorReason::HttpErrorCode(_)
+            | ErrorReason::ServerBusy(..)
+            | ErrorReason::ProxyErrorCode(_) => ErrorKind::HttpStatus,
+         ErrorReason::FailedRequest(ref err) if is_tls_failure(err) => ErrorKind::Tls,
+         ErrorReason::FailedRequest(_) => ErrorKind::Network,
+         ErrorReason::Tls(_) => ErrorKind::Tls,
+         ErrorReason::TooManyRedirects(_) | ErrorReason::ForbiddenRedirect(_) => {
+            ErrorKind::Redirect
+         }
+         ErrorReason::FileExists(_) => ErrorKind::FileExists,
+         ErrorReason::UnsupportedEncoding(_)
+            | ErrorReason::UnexpectedEncoding(_)
+            | ErrorReason::Decoding(_) => ErrorKind::Encoding,
+         ErrorReason::FailedThread(_) => ErrorKind::Internal,
+         ErrorReason::Multiple(_) => ErrorKind::Multiple
+      }
+   }
+
+   /// The status the server or the proxy answered with, if the error is an HTTP error status.
+   pub fn status(&self) -> Option<StatusCode> {
+      match *self.reason {
+         ErrorReason::HttpErrorCode(status)
+            | ErrorReason::ServerBusy(status, _)
+            | ErrorReason::ProxyErrorCode(status) => Some(status),
+         _ => None
+      }
+   }
+
+   /// The index of the part that failed, if the error happened in a single part.
+   pub fn part(&self) -> Option<u64> {
+      self.part.as_ref().map(|part| part.0)
+   }
+
+   /// The URL the failed part was downloaded from.
+   pub fn url(&self) -> Option<&Url> {
+      self.part.as_ref().map(|part| &part.1)
+   }
+
+   /// The status `rget` exits with because of this error.  If several parts failed for different
+   /// reasons, this is `ExitCode::Generic`.
+   pub fn exit_code(&self) -> ExitCode {
+      match *self.reason {
+         ErrorReason::IO(_) | ErrorReason::FileExists(_) | ErrorReason::NotEnoughSpace(..) => {
+            ExitCode::Io
+         }
+         ErrorReason::MissingUrl
+            | ErrorReason::InvalidConfig(_)
+            | ErrorReason::InvalidToml(_)
+            | ErrorReason::InvalidUrl(_)
+            | ErrorReason::InvalidProxy(_)
+            | ErrorReason::InvalidCertificate(_) => ExitCode::Parse,
+         ErrorReason::HttpErrorCode(StatusCode::Unauthorized)
+            | ErrorReason::HttpErrorCode(StatusCode::ProxyAuthenticationRequired)
+            | ErrorReason::ProxyErrorCode(StatusCode::ProxyAuthenticationRequired) => ExitCode::Auth,
+         ErrorReason::HttpErrorCode(_)
+            | ErrorReason::ServerBusy(..)
+            | ErrorReason::TooManyRedirects(_)
+            | ErrorReason::ForbiddenRedirect(_)
+            | ErrorReason::UnsupportedEncoding(_)
+            | ErrorReason::UnexpectedEncoding(_)
+            | ErrorReason::Decoding(_) => ExitCode::Server,
+         ErrorReason::FailedRequest(ref err) if is_tls_failure(err) => ExitCode::Tls,
+         ErrorReason::FailedRequest(_) | ErrorReason::ProxyErrorCode(_) => ExitCode::Network,
+         ErrorReason::Tls(_) => ExitCode::Tls,
+         ErrorReason::FailedThread(_) => ExitCode::Generic,
+         ErrorReason::Multiple(ref errors) => {
+            let mut codes = errors.iter().map(Error::exit_code);
+            match codes.next() {
+               Some(code) if codes.all(|other| other == code) => code,
+               _ => ExitCode::Generic
+            }
+         }
+      }
+   }
+
+   /// The errors of all failed parts if several failed, or just this error otherwise.
+   pub fn errors(&self) -> &[Error] {
+      match *self.reason {
+         ErrorReason::Multiple(ref errors) => errors,
+         _ => slice::from_ref(self)
       }
    }
 }
 
+/// Whether the request failed because the TLS handshake failed or the server presented the wrong
+/// public key, which the connector reports as an I/O error wrapping the TLS error.
+fn is_tls_failure(err: &hyper::Error) -> bool {
+   match *err {
+      hyper::Error::Io(ref err) => err.get_ref().is_some_and(|err| {
+         err.is::<native_tls::Error>() || err.is::<tls::PinnedKeyMismatch>()
+      }),
+      _ => false
+   }
+}
+
 impl ErrorReason {
    fn to_string(&self) -> String {
       match *self {
          ErrorReason::IO(ref err) => format!("{}", err),
          ErrorReason::MissingUrl => "no download configuration found and no valid URL given".to_string(),
          ErrorReason::HttpErrorCode(ref status) => format!("received {} from server", status),
+         ErrorReason::ServerBusy(ref status, Some(delay)) => {
+            format!("received {} from server (asked to retry after {} seconds)",
+                    status,
+                    delay.as_secs())
+         }
+         ErrorReason::ServerBusy(ref status, None) => format!("received {} from server", status),
          ErrorReason::FailedRequest(ref err) => format!("{}", err),
+         ErrorReason::ProxyErrorCode(ref status) => {
+            format!("proxy refused to open a tunnel: received {}", status)
+         }
          ErrorReason::InvalidConfig(msg) => msg.to_string(),
          ErrorReason::InvalidToml(ref err) => format!("invalid data in download configuration: {:?}", err),
          ErrorReason::InvalidUrl(ref err) => format!("{}", err),
+         ErrorReason::InvalidProxy(ref proxy) => format!("invalid or unsupported proxy: {}", proxy),
+         ErrorReason::InvalidCertificate(ref path) => {
+            format!("could not load certificates from {}", path.display())
+         }
+         ErrorReason::Tls(ref err) => format!("{}", err),
+         ErrorReason::TooManyRedirects(max) => format!("exceeded the maximum of {} redirects", max),
+         ErrorReason::ForbiddenRedirect(ref url) => {
+            format!("refusing to follow redirect to {} as it changes the URL scheme", url)
+         }
+         ErrorReason::NotEnoughSpace(ref dir, needed, available) => {
+            format!("not enough space in {}: the download needs {} bytes (including a copy to \
+                     merge the parts into) but only {} are available",
+                    dir.display(),
+                    needed,
+                    available)
+         }
+         ErrorReason::FileExists(ref path) => {
+            format!("{} already exists; use --overwrite, --backup or --no-clobber", path.display())
+         }
+         ErrorReason::UnsupportedEncoding(ref encoding) => {
+            format!("cannot decode content encoded with {}", encoding)
+         }
+         ErrorReason::UnexpectedEncoding(ref encoding) => {
+            format!("server sent part of the file encoded with {} despite being asked not to",
+                    encoding)
+         }
+         ErrorReason::Decoding(ref err) => format!("could not decode the content: {}", err),
          ErrorReason::FailedThread(ref err) => format!("{:?}", err),
          ErrorReason::Multiple(ref errors) => {
             errors.iter().fold("".to_string(), |acc, ref err| format!("{}\n{}", acc, err))
@@ -58,8 +315,66 @@ impl ErrorReason {
    }
 }
 
+impl FileError {
+   /// Wraps `error`, which happened while trying to `action` the file at `path`.
+   pub fn wrap<P: AsRef<Path>>(error: io::Error, action: &'static str, path: P) -> io::Error {
+      // errors that were already wrapped keep their original context
+      if error.get_ref().is_some_and(|inner| inner.is::<FileError>()) {
+         return error;
+      }
+      let kind = error.kind();
+      io::Error::new(kind, FileError {
+         action,
+         path: path.as_ref().to_path_buf(),
+         error
+      })
+   }
+
+   /// What was being done to the file, e.g. "create" or "write to".
+   pub fn action(&self) -> &str {
+      self.action
+   }
+
+   pub fn path(&self) -> &Path {
+      &self.path
+   }
+
+   pub fn io_error(&self) -> &io::Error {
+      &self.error
+   }
+}
+
+impl Display for FileError {
+   fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+      write!(fmt, "could not {} {}: {}", self.action, self.path.display(), self.error)
+   }
+}
+
+impl error::Error for FileError {
+   fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+      Some(&self.error)
+   }
+}
+
 impl Display for Error {
    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+      if let Some(ref part) = self.part {
+         let (part, ref url) = **part;
+         write!(fmt, "part {} of {}: ", part, url)?;
+      }
       write!(fmt, "{}", self.reason.to_string())
    }
 }
+
+impl error::Error for Error {
+   fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+      match *self.reason {
+         ErrorReason::IO(ref err) | ErrorReason::Decoding(ref err) => Some(err),
+         ErrorReason::FailedRequest(ref err) => Some(err),
+         ErrorReason::InvalidToml(ref err) => Some(err),
+         ErrorReason::InvalidUrl(ref err) => Some(err),
+         ErrorReason::Tls(ref err) => Some(err),
+         _ => None
+      }
+   }
+}
@@ -0,0 +1,206 @@
+// This file is part of rget.
+//
+// Copyright (C) 2016-2017 Arcterus (Alex Lyon) and rget contributors.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use hyper::header::Headers;
+use url::{percent_encoding, Url};
+
+const DEFAULT_NAME: &str = "index.html";
+
+/// Picks the name to save a download under.  In order of preference, this is the file name given
+/// by the `Content-Disposition` header, the last segment of the path of `url` (which should be
+/// the URL the file was served from after any redirects), or `index.html`.
+///
+/// The name never contains a path separator, so it cannot escape the output directory.
+pub fn resolve(headers: &Headers, url: &Url) -> String {
+   disposition_name(headers).and_then(|name| sanitize(&name))
+                            .or_else(|| url_name(url).and_then(|name| sanitize(&name)))
+                            .unwrap_or_else(|| DEFAULT_NAME.to_string())
+}
+
+fn disposition_name(headers: &Headers) -> Option<String> {
+   let value = match headers.get_raw("Content-Disposition").and_then(|raw| raw.one()) {
+      Some(value) => String::from_utf8_lossy(value).into_owned(),
+      None => return None
+   };
+
+   let mut plain = None;
+   let mut extended = None;
+   // the first section is the disposition type
+   for param in split_params(&value).into_iter().skip(1) {
+      let mut parts = param.splitn(2, '=');
+      let key = parts.next().unwrap_or("").trim().to_lowercase();
+      let val = parts.next().unwrap_or("").trim();
+      if key == "filename*" {
+         extended = decode_extended(val);
+      } else if key == "filename" {
+         plain = Some(unquote(val));
+      }
+   }
+   // RFC 6266: filename* takes precedence as it can represent any character
+   extended.or(plain)
+}
+
+/// Splits the header on `;`s that are not inside a quoted string.
+fn split_params(value: &str) -> Vec<&str> {
+   let mut params = vec![];
+   let mut quoted = false;
+   let mut escaped = false;
+   let mut start = 0;
+   for (i, c) in value.char_indices() {
+      match c {
+         _ if escaped => escaped = false,
+         '\\' if quoted => escaped = true,
+         '"' => quoted = !quoted,
+         ';' if !quoted => {
+            params.push(&value[start..i]);
+            start = i + 1;
+         }
+         _ => {}
+      }
+   }
+   params.push(&value[start..]);
+   params
+}
+
+fn unquote(value: &str) -> String {
+   if !(value.len() >= 2 && value.starts_with('"') && value.ends_with('"')) {
+      return value.to_string();
+   }
+   let mut result = String::new();
+   let mut chars = value[1..value.len() - 1].chars();
+   while let Some(c) = chars.next() {
+      if c == '\\' {
+         result.extend(chars.next());
+      } else {
+         result.push(c);
+      }
+   }
+   result
+}
+
+/// Decodes an RFC 5987 `charset'language'value` string.
+fn decode_extended(value: &str) -> Option<String> {
+   let mut parts = value.splitn(3, '\'');
+   let charset = parts.next().unwrap_or("").to_lowercase();
+   let encoded = match (parts.next(), parts.next()) {
+      (Some(_), Some(encoded)) => encoded,
+      _ => return None
+   };
+   let bytes: Vec<u8> = percent_encoding::percent_decode(encoded.as_bytes()).collect();
+   match &charset[..] {
+      "utf-8" => Some(String::from_utf8_lossy(&bytes).into_owned()),
+      // every byte of ISO-8859-1 is the Unicode code point of the same value
+      "iso-8859-1" => Some(bytes.iter().map(|&b| b as char).collect()),
+      _ => None
+   }
+}
+
+fn url_name(url: &Url) -> Option<String> {
+   url.path_segments()
+      .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+      .map(|segment| {
+         percent_encoding::percent_decode(segment.as_bytes()).decode_utf8_lossy().into_owned()
+      })
+}
+
+/// Reduces `name` to a plain file name, or `None` if nothing usable is left.
+fn sanitize(name: &str) -> Option<String> {
+   // only keep the last component of anything that looks like a path on any platform
+   let name = name.rsplit(&['/', '\\'][..]).next().unwrap_or("");
+   let name: String = name.chars()
+                          .map(|c| match c {
+                             '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+                             c if c.is_control() => '_',
+                             c => c
+                          })
+                          .collect();
+   // leading dots would hide the file, trailing dots and spaces are dropped by Windows
+   let name = name.trim().trim_start_matches('.').trim_end_matches(&['.', ' '][..]);
+   if name.is_empty() {
+      None
+   } else {
+      Some(name.to_string())
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn resolve_with(disposition: &str, url: &str) -> String {
+      let mut headers = Headers::new();
+      headers.set_raw("Content-Disposition", disposition.to_string());
+      resolve(&headers, &Url::parse(url).unwrap())
+   }
+
+   #[test]
+   fn strips_path_components() {
+      assert_eq!(sanitize("../../etc/passwd"), Some("passwd".to_string()));
+      assert_eq!(sanitize("..\\..\\windows\\system.ini"), Some("system.ini".to_string()));
+      assert_eq!(sanitize("/absolute/path.txt"), Some("path.txt".to_string()));
+      assert_eq!(sanitize("dir/"), None);
+   }
+
+   #[test]
+   fn rejects_dot_names() {
+      assert_eq!(sanitize(".."), None);
+      assert_eq!(sanitize("."), None);
+      assert_eq!(sanitize(" . "), None);
+      assert_eq!(sanitize(".hidden"), Some("hidden".to_string()));
+      assert_eq!(sanitize("name. . "), Some("name".to_string()));
+   }
+
+   #[test]
+   fn replaces_reserved_characters() {
+      assert_eq!(sanitize("a<b>c:d\"e|f?g*h"), Some("a_b_c_d_e_f_g_h".to_string()));
+      assert_eq!(sanitize("line\nbreak\0"), Some("line_break_".to_string()));
+   }
+
+   #[test]
+   fn decodes_extended_names() {
+      assert_eq!(decode_extended("UTF-8''na%C3%AFve%20file.bin"),
+                 Some("na\u{ef}ve file.bin".to_string()));
+      assert_eq!(decode_extended("utf-8'en'%E2%82%AC.txt"), Some("\u{20ac}.txt".to_string()));
+      assert_eq!(decode_extended("ISO-8859-1''caf%E9.txt"), Some("caf\u{e9}.txt".to_string()));
+      assert_eq!(decode_extended("UTF-16''x.txt"), None);
+      assert_eq!(decode_extended("x.txt"), None);
+   }
+
+   #[test]
+   fn prefers_the_extended_name() {
+      let disposition = "attachment; filename=\"plain.bin\"; filename*=UTF-8''%C3%BCber.bin";
+      assert_eq!(resolve_with(disposition, "http://host/path/url.bin"), "\u{fc}ber.bin");
+      let disposition = "attachment; filename*=UTF-8''%C3%BCber.bin; filename=plain.bin";
+      assert_eq!(resolve_with(disposition, "http://host/path/url.bin"), "\u{fc}ber.bin");
+   }
+
+   #[test]
+   fn reads_quoted_names() {
+      let disposition = "attachment; filename=\"semi;colon \\\"quoted\\\".txt\"";
+      assert_eq!(resolve_with(disposition, "http://host/"), "semi;colon _quoted_.txt");
+   }
+
+   #[test]
+   fn sanitizes_header_names() {
+      assert_eq!(resolve_with("attachment; filename=\"../../.bashrc\"", "http://host/f.bin"),
+                 "bashrc");
+      assert_eq!(resolve_with("attachment; filename*=UTF-8''..%2F..%2Fx", "http://host/f.bin"),
+                 "x");
+      assert_eq!(resolve_with("attachment; filename=\"..\"", "http://host/f.bin"), "f.bin");
+   }
+
+   #[test]
+   fn falls_back_to_the_url() {
+      assert_eq!(resolve(&Headers::new(), &Url::parse("http://host/a/b%20c.iso").unwrap()),
+                 "b c.iso");
+      assert_eq!(resolve(&Headers::new(), &Url::parse("http://host/dir/").unwrap()), "dir");
+      assert_eq!(resolve(&Headers::new(), &Url::parse("http://host/..%2F..").unwrap()),
+                 DEFAULT_NAME);
+      assert_eq!(resolve(&Headers::new(), &Url::parse("http://host").unwrap()), DEFAULT_NAME);
+   }
+}
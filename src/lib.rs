@@ -6,18 +6,50 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-extern crate reqwest;
+extern crate hyper;
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_tls;
+extern crate h2;
+extern crate http;
+extern crate bytes;
+extern crate url;
+extern crate native_tls;
+extern crate base64;
+extern crate flate2;
+extern crate brotli_decompressor;
+#[cfg(test)]
+extern crate brotli;
+extern crate zstd;
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+extern crate openssl;
+#[cfg(unix)]
+extern crate libc;
 extern crate term;
 extern crate pbr;
 extern crate toml;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 
 pub use network::Downloader;
 pub use output::OutputManager;
+pub use report::DownloadReport;
+pub use error::{Error, ErrorKind, ExitCode};
 
 pub mod network;
+pub mod client;
+pub mod compression;
+pub mod concurrency;
+pub mod filename;
 pub mod partial;
+pub mod progress;
+pub mod reassembly;
+pub mod report;
 pub mod util;
 pub mod error;
 pub mod output;
+pub mod proxy;
+pub mod tls;
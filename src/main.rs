@@ -8,61 +8,203 @@
 
 #[macro_use]
 extern crate clap;
-extern crate term;
 extern crate rget;
 
-use std::io::{Write};
+use std::fs::File;
 use std::process;
 
-use rget::Downloader;
-use rget::network::DownloaderConfig;
+use rget::{Downloader, ExitCode, OutputManager};
+use rget::network::{DownloaderConfig, OverwritePolicy, DEFAULT_MIN_PART_SIZE, STDOUT_NAME};
+use rget::output::{JsonOutputManager, StdOutputManager, Verbosity};
+use rget::tls::TlsVersion;
 
 const DEFAULT_PARALLEL: &'static str = "4";
+const AUTO_PARALLEL: &str = "auto";
+const EXIT_CODES: &'static str = "EXIT CODES:
+    0    success
+    1    generic error
+    2    invalid option, URL or download configuration
+    3    file I/O error
+    4    network failure
+    5    TLS failure
+    6    authentication required (401 or 407)
+    8    server error response (4xx or 5xx), bad redirect or unusable content
+    9    checksum mismatch
+    130  interrupted";
 
 fn main() {
     let matches = clap_app!(rget =>
       (version: crate_version!())
       (author: crate_authors!())
       (about: "Download accelerator written in Rust")
-      (@arg PARALLEL: -n --parallel +takes_value {is_number} /*default_value: "4"*/ "Number of parallel downloads")
-      (@arg OUTPUT:   -o --output   +takes_value "Output file name")
+      (after_help: EXIT_CODES)
+      (@arg PARALLEL: -n --parallel +takes_value {is_parallel} /*default_value: "4"*/ "Number of parallel downloads, or auto to pick it from the server's latency")
+      (@arg MIN_SPLIT: --("min-split-size") +takes_value {is_size} "Do not split the file into parts smaller than this (e.g. 512K, 4M; default: 1M)")
+      (@arg MAX_CONNECTIONS: --("max-connections-per-host") +takes_value {is_connections} "Maximum number of connections to the server")
+      (@arg ADAPTIVE: --adaptive "Start with 2 connections and add more (up to one per part) while the download gets faster")
+      (@arg MAX_STREAMS: --("max-streams-per-connection") +takes_value {is_streams} "Maximum number of parts to fetch over one HTTP/2 connection (default: all)")
+      (@arg NO_HTTP2: --("no-http2") conflicts_with[MAX_STREAMS] "Do not offer HTTP/2 to HTTPS servers")
+      (@arg OUTPUT:   -o --output   +takes_value "Output file name (- for standard output)")
       (@arg USERNAME: -u --user     +takes_value "Username")
       (@arg PASSWORD: -p --password +takes_value "Password")
-      (@arg INSECURE: --insecure "Disable hostname verification")
+      (@arg NO_VERIFY_HOSTNAME: --("no-verify-hostname") "INSECURE: do not check that the certificate matches the host name")
+      (@arg NO_VERIFY_CERT: --("no-verify-certificate") "INSECURE: accept any certificate, even an invalid or self-signed one")
+      (@arg INSECURE: --insecure +hidden "Deprecated alias for --no-verify-hostname")
+      (@arg MIN_TLS: --("min-tls-version") +takes_value {is_tls_version} "Minimum TLS version the server must support (1.0, 1.1 or 1.2)")
+      (@arg MAX_REDIRECTS: --("max-redirects") +takes_value {is_count} "Maximum number of redirects to follow (default: 10)")
+      (@arg CROSS_SCHEME: --("allow-cross-scheme") "Follow redirects from HTTPS to another scheme")
+      (@arg PROXY: -x --proxy +takes_value "Proxy to use (http://, socks5:// or socks5h://)")
+      (@arg PROXY_USER: --("proxy-user") +takes_value "Username for the proxy")
+      (@arg PROXY_PASSWORD: --("proxy-password") +takes_value "Password for the proxy")
+      (@arg CA_CERT: --("ca-cert") +takes_value "CA certificate(s) to trust, PEM or DER encoded")
+      (@arg PINNED_KEY: --("pinned-pubkey") +takes_value "Public key hash(es) the server must present (sha256//<base64>;...)")
+      (@arg CLIENT_CERT: --cert +takes_value "Client certificate to authenticate with, PEM encoded or PKCS#12")
+      (@arg CLIENT_KEY: --key +takes_value requires[CLIENT_CERT] "Private key of a PEM encoded client certificate (default: read from --cert)")
+      (@arg CERT_PASSWORD: --("cert-password") +takes_value requires[CLIENT_CERT] "Password of a PKCS#12 client certificate")
+      (@arg NO_PROXY: --("no-proxy") +takes_value "Comma-separated list of hosts to connect to directly")
+      (@arg DIR: -d --dir +takes_value "Directory to save the file in")
+      (@arg NO_CLOBBER: --("no-clobber") conflicts_with[BACKUP OVERWRITE] "Skip the download if the output file already exists")
+      (@arg BACKUP: --backup conflicts_with[OVERWRITE] "Move an existing output file to <file>.1, <file>.2, ...")
+      (@arg OVERWRITE: --overwrite "Replace an existing output file")
+      (@arg COMPRESSED: --compressed "Request a compressed transfer and decode it (uses a single connection)")
+      (@arg PREALLOCATE: --preallocate "Reserve disk space for the file before downloading it (Linux only)")
+      (@arg QUIET: -q --quiet conflicts_with[VERBOSE] "Only print errors")
+      (@arg VERBOSE: -v --verbose ... "Print the byte ranges and status of requests (-vv: also their headers)")
+      (@arg COMPACT: --compact "Show a single line of progress instead of a bar for every part")
+      (@arg LOG_FILE: --("log-file") +takes_value "Also write messages to this file")
+      (@arg OUTPUT_FORMAT: --("output-format") +takes_value possible_value[text json] "Print messages as text or as newline-delimited JSON (default: text)")
       (@arg INPUT: +required "URL of the file to download")
       (@subcommand validate =>
          (about: "Validates a downloaded file")
       )
-   ).get_matches();
+   ).get_matches_safe().unwrap_or_else(|f| {
+      match f.kind {
+         clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => f.exit(),
+         _ => {
+            eprintln!("{}", f.message);
+            exit(ExitCode::Parse)
+         }
+      }
+   });
 
-   let mut stderr = term::stderr().unwrap();
+   // for messages from before the download starts
+   let mut console = StdOutputManager::stderr_only();
 
-   let parallel = match matches.value_of("PARALLEL").unwrap_or(DEFAULT_PARALLEL).parse::<u64>() {
+   let auto_parallel = matches.value_of("PARALLEL") == Some(AUTO_PARALLEL);
+   let parallel = match matches.value_of("PARALLEL")
+                               .filter(|_| !auto_parallel)
+                               .unwrap_or(DEFAULT_PARALLEL)
+                               .parse::<u64>() {
       Ok(m) => m,
       Err(f) => {
-         stderr.fg(term::color::RED).unwrap();
-         writeln!(stderr, "{}", f).unwrap();
-         process::exit(1)
+         console.error(&f.to_string());
+         exit(ExitCode::Parse)
       }
    };
    let input = matches.value_of("INPUT").unwrap();
+   let max_redirects = matches.value_of("MAX_REDIRECTS").map(|num| num.parse::<usize>().unwrap());
 
    if let Some(_) = matches.subcommand_matches("validate") {
       unimplemented!();
    } else {
-      let config = DownloaderConfig {
+      let mut config = DownloaderConfig {
           username: matches.value_of("USERNAME").map(Into::into),
           password: matches.value_of("PASSWORD").map(Into::into),
-          insecure: matches.is_present("INSECURE")
+          accept_invalid_hostnames: matches.is_present("NO_VERIFY_HOSTNAME") || matches.is_present("INSECURE"),
+          accept_invalid_certs: matches.is_present("NO_VERIFY_CERT"),
+          min_tls_version: matches.value_of("MIN_TLS").map(|version| version.parse().unwrap()),
+          allow_cross_scheme: matches.is_present("CROSS_SCHEME"),
+          proxy: matches.value_of("PROXY").map(Into::into),
+          proxy_username: matches.value_of("PROXY_USER").map(Into::into),
+          proxy_password: matches.value_of("PROXY_PASSWORD").map(Into::into),
+          no_proxy: matches.value_of("NO_PROXY").map(Into::into),
+          ca_cert: matches.value_of("CA_CERT").map(Into::into),
+          pinned_public_key: matches.value_of("PINNED_KEY").map(Into::into),
+          client_cert: matches.value_of("CLIENT_CERT").map(Into::into),
+          client_key: matches.value_of("CLIENT_KEY").map(Into::into),
+          client_cert_password: matches.value_of("CERT_PASSWORD").map(Into::into),
+          compressed: matches.is_present("COMPRESSED"),
+          preallocate: matches.is_present("PREALLOCATE"),
+          min_part_size: matches.value_of("MIN_SPLIT")
+                                .map_or(DEFAULT_MIN_PART_SIZE, |size| parse_size(size).unwrap()),
+          max_connections_per_host: matches.value_of("MAX_CONNECTIONS").map(|num| num.parse().unwrap()),
+          http2: !matches.is_present("NO_HTTP2"),
+          max_streams_per_connection: matches.value_of("MAX_STREAMS").map(|num| num.parse().unwrap()),
+          auto_parallel,
+          adaptive: matches.is_present("ADAPTIVE"),
+          output_dir: matches.value_of("DIR").map(Into::into),
+          overwrite: if matches.is_present("NO_CLOBBER") {
+             OverwritePolicy::NoClobber
+          } else if matches.is_present("BACKUP") {
+             OverwritePolicy::Backup
+          } else if matches.is_present("OVERWRITE") {
+             OverwritePolicy::Overwrite
+          } else {
+             OverwritePolicy::Refuse
+          },
+          ..DownloaderConfig::default()
       };
-      let mut downloader = Downloader::new(parallel, config);
-      if let Err(f) = downloader.download(input, matches.value_of("OUTPUT")) {
-         stderr.fg(term::color::RED).unwrap();
-         writeln!(stderr, "error: {}", f).unwrap();
+      if let Some(max) = max_redirects {
+          config.max_redirects = max;
+      }
+      if matches.is_present("INSECURE") {
+         console.warn("--insecure is deprecated and only disables hostname verification; \
+                       use --no-verify-hostname instead");
+      }
+      let output = matches.value_of("OUTPUT");
+      if matches.value_of("OUTPUT_FORMAT") == Some("json") {
+         if output == Some(STDOUT_NAME) {
+            console.error("JSON output cannot be used while writing the file to standard output");
+            exit(ExitCode::Parse)
+         }
+         if matches.is_present("LOG_FILE") {
+            console.error("--log-file can only be used with text output");
+            exit(ExitCode::Parse)
+         }
+         download(Downloader::with_output_manager(parallel, config, JsonOutputManager::new()),
+                  input,
+                  output);
+      } else {
+         let mut manager = if output == Some(STDOUT_NAME) {
+            StdOutputManager::stderr_only()
+         } else {
+            StdOutputManager::new()
+         };
+         manager.set_verbosity(if matches.is_present("QUIET") {
+            Verbosity::Quiet
+         } else {
+            match matches.occurrences_of("VERBOSE") {
+               0 => Verbosity::Normal,
+               1 => Verbosity::Verbose,
+               _ => Verbosity::Debug
+            }
+         });
+         manager.set_compact_progress(matches.is_present("COMPACT"));
+         if let Some(path) = matches.value_of("LOG_FILE") {
+            match File::create(path) {
+               Ok(file) => manager.set_log_file(file),
+               Err(f) => {
+                  console.error(&format!("could not create {}: {}", path, f));
+                  exit(ExitCode::Io)
+               }
+            }
+         }
+         download(Downloader::with_output_manager(parallel, config, manager), input, output);
       }
    }
 }
 
+fn download<T: OutputManager>(mut downloader: Downloader<T>, input: &str, output: Option<&str>) {
+   if let Err(f) = downloader.download(input, output) {
+      downloader.output_manager().error(&f.to_string());
+      exit(f.exit_code());
+   }
+}
+
+fn exit(code: ExitCode) -> ! {
+   process::exit(code as i32)
+}
+
 fn is_number(input: String) -> Result<(), String> {
    match input.parse::<u64>() {
       Ok(num) => if num > 0 {
@@ -73,3 +215,93 @@ fn is_number(input: String) -> Result<(), String> {
       Err(_) => Err(String::from("the number of parallel downloads must be an integer"))
    }
 }
+
+fn is_parallel(input: String) -> Result<(), String> {
+   if input == AUTO_PARALLEL {
+      Ok(())
+   } else {
+      is_number(input)
+   }
+}
+
+fn is_connections(input: String) -> Result<(), String> {
+   match input.parse::<u64>() {
+      Ok(num) => if num > 0 {
+         Ok(())
+      } else {
+         Err(String::from("the number of connections per host must be greater than 0"))
+      },
+      Err(_) => Err(String::from("the number of connections per host must be an integer"))
+   }
+}
+
+fn is_size(input: String) -> Result<(), String> {
+   parse_size(&input).map(|_| ())
+}
+
+/// Parses a number of bytes with an optional K, M or G suffix (powers of 1024).
+fn parse_size(input: &str) -> Result<u64, String> {
+   let (digits, unit) = match input.char_indices().find(|&(_, c)| !c.is_ascii_digit()) {
+      Some((index, _)) => input.split_at(index),
+      None => (input, "")
+   };
+   let multiplier = match unit.to_ascii_uppercase().as_str() {
+      "" | "B" => 1,
+      "K" | "KB" | "KIB" => 1024,
+      "M" | "MB" | "MIB" => 1024 * 1024,
+      "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+      _ => return Err(format!("unknown size unit: {}", unit))
+   };
+   match digits.parse::<u64>().ok().and_then(|num| num.checked_mul(multiplier)) {
+      Some(size) => Ok(size),
+      None => Err(String::from("the size must be a non-negative integer, optionally followed by K, M or G"))
+   }
+}
+
+fn is_count(input: String) -> Result<(), String> {
+   match input.parse::<usize>() {
+      Ok(_) => Ok(()),
+      Err(_) => Err(String::from("the value must be a non-negative integer"))
+   }
+}
+
+fn is_streams(input: String) -> Result<(), String> {
+   match input.parse::<u64>() {
+      Ok(num) => if num > 0 {
+         Ok(())
+      } else {
+         Err(String::from("the number of streams per connection must be greater than 0"))
+      },
+      Err(_) => Err(String::from("the number of streams per connection must be an integer"))
+   }
+}
+
+fn is_tls_version(input: String) -> Result<(), String> {
+   input.parse::<TlsVersion>().map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn parses_sizes_with_units() {
+      assert_eq!(parse_size("0"), Ok(0));
+      assert_eq!(parse_size("512"), Ok(512));
+      assert_eq!(parse_size("512b"), Ok(512));
+      assert_eq!(parse_size("4K"), Ok(4 * 1024));
+      assert_eq!(parse_size("4kib"), Ok(4 * 1024));
+      assert_eq!(parse_size("3M"), Ok(3 * 1024 * 1024));
+      assert_eq!(parse_size("2GB"), Ok(2 * 1024 * 1024 * 1024));
+   }
+
+   #[test]
+   fn rejects_invalid_sizes() {
+      assert!(parse_size("").is_err());
+      assert!(parse_size("M").is_err());
+      assert!(parse_size("-1").is_err());
+      assert!(parse_size("1.5M").is_err());
+      assert!(parse_size("4T").is_err());
+      assert!(parse_size("99999999999G").is_err());
+   }
+}
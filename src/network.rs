@@ -6,45 +6,242 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use reqwest::{Client, Url};
-use reqwest::StatusCode;
-use reqwest::header::{
+use futures::{future, Future, Stream};
+use futures::future::{Either, Loop};
+use hyper::{HttpVersion, StatusCode};
+use hyper::header::{
     Authorization,
     Basic,
     ByteRangeSpec,
     ContentLength,
+    ContentType,
+    ETag,
+    Headers,
+    LastModified,
     /*ContentRange,*/
+    Location,
     Range,
+    RetryAfter,
 };
 use std::u64;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
 use std::path::{Path, PathBuf};
-use std::io::{self, BufWriter, Read, Write};
-use std::thread;
-use std::sync::Arc;
-use std::time::Duration;
-use std::mem;
-use pbr::{MultiBar, ProgressBar, Units};
+use std::io::{self, BufWriter, Cursor, Read, Seek, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_core::reactor::Core;
 use toml::{self, Value};
+use url::Url;
 
+use client::{Client, Response};
+use compression;
+use concurrency::{self, Concurrency, ScheduleEvent, Scheduler, StartPart, Throughput};
+use filename;
 use partial::FilePart;
+use progress::{ProgressBars, ProgressEvent, ProgressListener, ProgressStyle};
+use reassembly::{Reassembler, SeekWriter};
+use report::{DownloadReport, PartReport, ReportHeaders};
+use tls::TlsVersion;
 use util;
-use error::{self, Error, ErrorReason};
-use output::{OutputManager, StdOutputManager};
+use error::{self, Error, ErrorReason, FileError};
+use output::{OutputManager, StdOutputManager, Verbosity};
 
-const PRINT_DELAY: u64 = 100;
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+const TEMP_EXTENSION: &str = "rget-tmp";
+/// Output name that makes rget write the file to standard output.
+pub const STDOUT_NAME: &str = "-";
+/// Memory the parts of a download to standard output may use while waiting for their turn, before
+/// they spill to disk.
+const STREAM_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+/// Files are not split into parts smaller than this by default.
+pub const DEFAULT_MIN_PART_SIZE: u64 = 1024 * 1024;
+/// The protocol reported for responses sent over HTTP/2.
+const HTTP2_NAME: &str = "HTTP/2";
+/// The most parts the automatic part count splits a file into.
+const MAX_AUTO_PARALLEL: u64 = 16;
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct DownloaderConfig {
     pub username: Option<String>,
     pub password: Option<String>,
-    pub insecure: bool,
+    /// Skip checking that the server's certificate is valid for its host name.
+    pub accept_invalid_hostnames: bool,
+    /// Accept any certificate the server presents, even an expired, self-signed or otherwise
+    /// invalid one, for any host name.
+    pub accept_invalid_certs: bool,
+    /// Refuse servers that do not support at least this TLS version.
+    pub min_tls_version: Option<TlsVersion>,
+    /// Maximum number of redirects followed for a single request.
+    pub max_redirects: usize,
+    /// Follow redirects that downgrade the scheme (e.g. HTTPS to HTTP).  Upgrading from HTTP to
+    /// HTTPS is always allowed.
+    pub allow_cross_scheme: bool,
+    /// Proxy for all requests, e.g. `http://proxy:3128` or `socks5://bastion:1080`.  If unset,
+    /// the `http_proxy`, `https_proxy` and `all_proxy` environment variables are consulted.
+    pub proxy: Option<String>,
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<String>,
+    /// Comma-separated hosts (and their subdomains) to connect to directly.  Overrides the
+    /// `no_proxy` environment variable.
+    pub no_proxy: Option<String>,
+    /// PEM or DER encoded certificate(s) to trust in addition to the system's root certificates.
+    pub ca_cert: Option<PathBuf>,
+    /// SHA-256 hashes of the public keys the server may present, as `sha256//<base64>` separated
+    /// by `;`.  Checked on every connection before anything is sent over it.
+    pub pinned_public_key: Option<String>,
+    /// Client certificate to present to servers that ask for one, either PEM encoded or a
+    /// PKCS#12 archive.
+    pub client_cert: Option<PathBuf>,
+    /// PEM encoded private key of `client_cert`.  If unset, it is read from `client_cert`.
+    pub client_key: Option<PathBuf>,
+    /// Password `client_cert` is encrypted with if it is a PKCS#12 archive.
+    pub client_cert_password: Option<String>,
+    /// Ask the server to compress the file and decode it while downloading.  As the parts of a
+    /// compressed file cannot be requested separately, this uses a single connection.
+    pub compressed: bool,
+    /// Directory to save downloads in.  Created if it does not exist yet.
+    pub output_dir: Option<PathBuf>,
+    /// What to do if the output file already exists.
+    pub overwrite: OverwritePolicy,
+    /// Reserve disk space for the parts before downloading them.  Only supported on Linux.
+    pub preallocate: bool,
+    /// Files are not split into parts smaller than this, so small files use fewer connections
+    /// than asked for.  0 splits any file.
+    pub min_part_size: u64,
+    /// The most connections to open to the host a file is downloaded from.
+    pub max_connections_per_host: Option<u64>,
+    /// Offer HTTP/2 to HTTPS servers, and fetch the parts from those that accept it as streams
+    /// over shared connections.
+    pub http2: bool,
+    /// The most parts fetched over one HTTP/2 connection at the same time, with more connections
+    /// opened for the rest.  If unset, all parts share a single connection.
+    pub max_streams_per_connection: Option<u64>,
+    /// Pick the number of parts from how long the server took to respond instead of using the
+    /// number given to the `Downloader`.
+    pub auto_parallel: bool,
+    /// Start with a few connections and add more while the download keeps getting faster, up to
+    /// one for every part.
+    pub adaptive: bool,
+}
+
+/// What to do with an existing file at the output path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Fail with `ErrorReason::FileExists`.
+    #[default]
+    Refuse,
+    /// Leave the existing file alone and skip the download.
+    NoClobber,
+    /// Move the existing file to the first free `<name>.1`, `<name>.2`, ... once the download
+    /// has finished.
+    Backup,
+    /// Replace the existing file once the download has finished.
+    Overwrite,
+}
+
+
+impl Default for DownloaderConfig {
+    fn default() -> DownloaderConfig {
+        DownloaderConfig {
+            username: None,
+            password: None,
+            accept_invalid_hostnames: false,
+            accept_invalid_certs: false,
+            min_tls_version: None,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            allow_cross_scheme: false,
+            proxy: None,
+            proxy_username: None,
+            proxy_password: None,
+            no_proxy: None,
+            ca_cert: None,
+            pinned_public_key: None,
+            client_cert: None,
+            client_key: None,
+            client_cert_password: None,
+            compressed: false,
+            output_dir: None,
+            overwrite: OverwritePolicy::default(),
+            preallocate: false,
+            min_part_size: DEFAULT_MIN_PART_SIZE,
+            max_connections_per_host: None,
+            http2: true,
+            max_streams_per_connection: None,
+            auto_parallel: false,
+            adaptive: false,
+        }
+    }
 }
 
 pub struct Downloader<T: OutputManager> {
    parallel: u64,
    config: DownloaderConfig,
-   output: T
+   output: T,
+   engine: Option<Engine>,
+   listeners: Vec<Box<dyn ProgressListener>>
+}
+
+/// Where a download is written to.
+enum Destination<'a> {
+   /// Parts are downloaded into files next to this path and merged into it at the end.
+   File(PathBuf),
+   /// The file is written to standard output in order as the parts come in.
+   Stdout,
+   /// Every part is written at its offset into the sink as it comes in.
+   Sink(Rc<RefCell<&'a mut dyn SeekWrite>>)
+}
+
+/// A sink for `Downloader::download_to()`.
+trait SeekWrite: Write + Seek {}
+
+impl<W: Write + Seek> SeekWrite for W {}
+
+/// What the initial request revealed about the file being downloaded.
+struct RemoteFile {
+   /// The URL the file was served from after following any redirects.
+   url: Url,
+   length: Option<u64>,
+   /// Whether the server compressed the response even though it was asked not to.
+   encoded: bool,
+   /// The name to save the file under if none was given.
+   name: String,
+   headers: ReportHeaders,
+   /// The HTTP version the server answered with.
+   protocol: Option<String>,
+   /// How long the server took to respond, including any redirects.
+   latency: Duration
+}
+
+/// Delivers the events of a running download to its progress bars as well as the output manager
+/// and listeners of the `Downloader`.
+struct Progress<'o, T: OutputManager + 'o> {
+   bars: Option<ProgressBars>,
+   output: &'o mut T,
+   listeners: &'o mut Vec<Box<dyn ProgressListener>>
+}
+
+impl<'o, T: OutputManager> Progress<'o, T> {
+   fn emit(&mut self, event: ProgressEvent) {
+      if let Some(ref mut bars) = self.bars {
+         bars.event(&event);
+      }
+      self.output.progress(&event);
+      for listener in self.listeners.iter_mut() {
+         listener.event(&event);
+      }
+   }
+}
+
+/// The event loop all downloads run on, along with the client whose connection pool they share.
+/// It is started by the first download and then reused by every later one.
+struct Engine {
+   core: Core,
+   client: Client,
+   /// The most connections each host (`host:port`) accepted before pushing back, so that later
+   /// downloads from it do not open more.
+   host_limits: HashMap<String, usize>
 }
 
 impl Downloader<StdOutputManager> {
@@ -58,42 +255,233 @@ impl<T: OutputManager> Downloader<T> {
       Downloader {
          parallel: parallel,
          config: config,
-         output: output
+         output: output,
+         engine: None,
+         listeners: vec![]
+      }
+   }
+
+   pub fn output_manager(&mut self) -> &mut T {
+      &mut self.output
+   }
+
+   /// Adds a listener that receives the progress events of every download from now on.
+   pub fn add_listener<L: ProgressListener + 'static>(&mut self, listener: L) {
+      self.listeners.push(Box::new(listener));
+   }
+
+   /// Downloads `input` to `output`, blocking until the download has finished.  All of the parts
+   /// are fetched concurrently on a single event loop rather than on a thread each.
+   pub fn download(&mut self,
+                   input: &str,
+                   output: Option<&str>) -> error::Result<DownloadReport> {
+      let result = self.with_engine(|downloader, engine| {
+         downloader.download_input(engine, input, output)
+      });
+      if let Ok(ref report) = result {
+         self.output.report(report);
+      }
+      if output == Some(STDOUT_NAME) {
+         self.output.stdout_taken(false);
       }
+      result
+   }
+
+   /// Downloads `url` into `sink`, blocking until the download has finished.  The parts are
+   /// still fetched in parallel, each being written at its offset in the sink.  Nothing is
+   /// written to disk, so such a download cannot be resumed.
+   pub fn download_to<W: Write + Seek>(&mut self,
+                                       url: &str,
+                                       sink: &mut W) -> error::Result<DownloadReport> {
+      let url = match Url::parse(url) {
+         Ok(url) => url,
+         Err(f) => return Err(Error::new(ErrorReason::InvalidUrl(f)))
+      };
+      let report = self.with_engine(|downloader, engine| {
+         let remote = downloader.probe(engine, &url)?;
+         let parallel = downloader.parallel;
+         let sink = Rc::new(RefCell::new(sink as &mut dyn SeekWrite));
+         downloader.download_url(engine, url, remote, Destination::Sink(sink), parallel, true)
+      })?;
+      self.output.report(&report);
+      Ok(report)
    }
 
-   pub fn download(&mut self, input: &str, output: Option<&str>) -> error::Result<()> {
-      let (output_path, url) = match Url::parse(input) {
+   /// Downloads `url` into memory.  See `download_to()`.
+   pub fn download_to_vec(&mut self, url: &str) -> error::Result<Vec<u8>> {
+      let mut data = Cursor::new(vec![]);
+      self.download_to(url, &mut data)?;
+      Ok(data.into_inner())
+   }
+
+   /// Sends the initial request for `url`.  See `probe()`.
+   fn probe(&mut self, engine: &mut Engine, url: &Url) -> error::Result<RemoteFile> {
+      let probe = probe(&engine.client, &self.config, url.clone(), tracer(&mut self.output));
+      engine.core.run(probe)
+   }
+
+   /// Runs `f` with the event loop, starting it first if this is the first download.
+   fn with_engine<F, R>(&mut self, f: F) -> error::Result<R>
+      where F: FnOnce(&mut Downloader<T>, &mut Engine) -> error::Result<R>
+   {
+      let mut engine = match self.engine.take() {
+         Some(engine) => engine,
+         None => self.start_engine()?
+      };
+      let result = f(self, &mut engine);
+      self.engine = Some(engine);
+      result
+   }
+
+   fn download_input(&mut self,
+                     engine: &mut Engine,
+                     input: &str,
+                     output: Option<&str>) -> error::Result<DownloadReport> {
+      if output == Some(STDOUT_NAME) {
+         // messages must not end up in the middle of the file
+         self.output.stdout_taken(true);
+         let url = match Url::parse(input) {
+            Ok(ref url) if url.scheme() != "file" => url.clone(),
+            _ => return Err(Error::new(ErrorReason::InvalidConfig(
+                                       "only URLs can be downloaded to standard output")))
+         };
+         let remote = self.probe(engine, &url)?;
+         let parallel = self.parallel;
+         // there is nothing to resume from, so start from scratch
+         return self.download_url(engine, url, remote, Destination::Stdout, parallel, true);
+      }
+
+      // the parts fetch the resolved URL directly rather than each following the redirects again
+      // (signed CDN redirects may hand out a different edge URL for every request)
+      let (output_path, url, remote) = match Url::parse(input) {
          Ok(ref url) if url.scheme() != "file" => {
-            // FIXME: still won't work if last character in url is /
-            let closure = || input.rsplit('/').next().unwrap();
-            (Path::new(output.unwrap_or_else(closure)), Some(url.clone()))
+            // without an output name, it comes from the response, so the request has to be made
+            // before looking for the file or the state of an earlier download
+            let (mut output_path, remote) = match output {
+               Some(output) => (PathBuf::from(output), None),
+               None => {
+                  let remote = self.probe(engine, url)?;
+                  self.output.info(&format!("saving to {}", remote.name));
+                  (PathBuf::from(&remote.name), Some(remote))
+               }
+            };
+            if let Some(ref dir) = self.config.output_dir {
+               if let Err(f) = fs::create_dir_all(dir) {
+                  return Err(Error::new(ErrorReason::IO(f)));
+               }
+               output_path = dir.join(output_path);
+            }
+            (output_path, Some(url.clone()), remote)
          }
          _ => {
             let closure = || input.trim_left_matches("file://").trim_right_matches(".toml");
-            (Path::new(output.unwrap_or_else(closure)), None)
+            (PathBuf::from(output.unwrap_or_else(closure)), None, None)
+         }
+      };
+      let (parallel, url, scratch) = self.reload_state(&output_path, url)?;
+      if output_path.exists() {
+         match self.config.overwrite {
+            OverwritePolicy::Refuse => {
+               return Err(Error::new(ErrorReason::FileExists(output_path)));
+            }
+            OverwritePolicy::NoClobber => {
+               self.output.info(&format!("{} already exists, not downloading",
+                                         output_path.display()));
+               return Ok(DownloadReport::skipped(output_path, url));
+            }
+            // dealt with once the download has finished, so the existing file stays in place if
+            // it fails
+            OverwritePolicy::Backup | OverwritePolicy::Overwrite => {}
          }
+      }
+      // unless the output name came from it, nothing is requested before this point, so a
+      // download that is refused or skipped sends no request at all
+      let remote = match remote {
+         Some(remote) => remote,
+         None => self.probe(engine, &url)?
       };
-      let (parallel, url, scratch) = self.reload_state(output_path, url)?;
-      self.download_url(url, output_path, parallel, scratch)
+
+      self.download_url(engine, url, remote, Destination::File(output_path), parallel, scratch)
    }
 
-   fn download_url<P: AsRef<Path>>(&mut self,
-                                   url: Url,
-                                   output: P,
-                                   mut parallel: u64,
-                                   mut scratch: bool) -> error::Result<()> {
-      // Apparently Client contains a connection pool, so reuse the same Client
-      let mut client_builder = Client::builder().unwrap();
-      if self.config.insecure {
-          client_builder.danger_disable_hostname_verification();
+   fn start_engine(&mut self) -> error::Result<Engine> {
+      let core = match Core::new() {
+         Ok(m) => m,
+         Err(f) => return Err(Error::new(ErrorReason::IO(f)))
+      };
+
+      if self.config.accept_invalid_hostnames {
+         self.output.warn("HOSTNAME VERIFICATION IS DISABLED: the server's certificate is not \
+                           checked against its host name, so the connection can be intercepted");
+      }
+      if self.config.accept_invalid_certs {
+         self.output.warn("CERTIFICATE VERIFICATION IS DISABLED: any certificate the server \
+                           presents is accepted, so the connection can be intercepted");
       }
-      let client = Arc::new(client_builder.build().unwrap());
-      //let config = Arc::new(self.config.clone());
+      if let Some(version) = self.config.min_tls_version {
+         self.output.info(&format!("requiring at least {}", version));
+      }
+      // Apparently Client contains a connection pool, so reuse the same Client
+      let client = Client::new(&self.config, &core.handle())?;
 
-      let length = match self.get_length(client.clone(), url.clone()) {
+      Ok(Engine {
+         core,
+         client,
+         host_limits: HashMap::new()
+      })
+   }
+
+   fn download_url<'a>(&mut self,
+                       engine: &mut Engine,
+                       url: Url,
+                       remote: RemoteFile,
+                       destination: Destination<'a>,
+                       mut parallel: u64,
+                       mut scratch: bool) -> error::Result<DownloadReport> {
+      let start = Instant::now();
+      let RemoteFile { url: resolved_url, length, encoded, headers, protocol, latency, .. } =
+         remote;
+      if resolved_url != url {
+         self.output.info(&format!("resolved to {}", resolved_url));
+      }
+      let http2 = protocol.as_ref().is_some_and(|protocol| protocol == HTTP2_NAME);
+      if let Some(ref protocol) = protocol {
+         self.output.info(&format!("protocol: {}", protocol));
+      }
+      let length = match length {
+         _ if self.config.compressed => {
+            if parallel > 1 {
+               self.output.warn("compressed files cannot be split into parts, disabling parallel \
+                                 download");
+            }
+            scratch = true;
+            parallel = 1;
+            None
+         }
+         _ if encoded => {
+            self.output.warn("server compresses the file even though it was asked not to, \
+                              disabling parallel download");
+            scratch = true;
+            parallel = 1;
+            None
+         }
          Some(length) => {
             self.output.info(&format!("remote file size: {} bytes", length));
+            // a download that is resumed has to keep the parts it was started with
+            if scratch {
+               let planned = plan_parts(&self.config, parallel, length, latency, http2);
+               if self.config.auto_parallel {
+                  self.output.verbose(&format!("server responded in {} ms, splitting into {} parts",
+                                               latency.as_millis(),
+                                               planned));
+               } else if planned < parallel {
+                  self.output.verbose(&format!("splitting into {} parts instead of {} to respect \
+                                                the minimum part size and connection limit",
+                                               planned,
+                                               parallel));
+               }
+               parallel = planned;
+            }
             Some(length)
          }
          None => {
@@ -105,181 +493,376 @@ impl<T: OutputManager> Downloader<T> {
          }
       };
 
-      self.output.info(&format!("using a total of {} connections", parallel));
-
-      let mut children = vec![];
-      let mut mb = MultiBar::new();
-
-      for i in 0u64..parallel {
-         let url = url.clone();
-         let output = output.as_ref().to_path_buf();
-         let client = client.clone();
-         let config = self.config.clone();
-         let mut progbar = mb.create_bar(100);
-
-         progbar.set_max_refresh_rate(Some(Duration::from_millis(PRINT_DELAY)));
-         progbar.show_message = true;
-         progbar.set_units(Units::Bytes);
-
-         children.push(thread::spawn(move || {
-            Downloader::<T>::download_callback(i,
-                                               progbar,
-                                               client,
-                                               url,
-                                               output,
-                                               length,
-                                               parallel,
-                                               config,
-                                               scratch)
-         }));
+      if http2 {
+         let connections = match self.config.max_streams_per_connection {
+            Some(streams) => parallel.div_ceil(streams.max(1)),
+            None => 1
+         };
+         let limit = if self.config.adaptive && parallel > 1 { "up to " } else { "" };
+         self.output.info(&format!("using {}{} streams over {} connections",
+                                   limit,
+                                   parallel,
+                                   connections));
+      } else if self.config.adaptive && parallel > 1 {
+         self.output.info(&format!("using up to {} connections", parallel));
+      } else {
+         self.output.info(&format!("using a total of {} connections", parallel));
       }
 
-      if scratch {
-         if let Err(f) = self.create_download_config(output.as_ref(), url, parallel) {
-            self.output.error(&f);  // continue, but let the user know that they can't stop the download
-         }
+      let mut part_config = self.config.clone();
+      if !forwards_credentials(&url, &resolved_url) {
+         part_config.username = None;
+         part_config.password = None;
       }
 
-      mb.listen();
-
-      let mut errors = vec![];
-      for child in children {
-         match child.join() {
-            Ok(Err(f)) => errors.push(f),
-            Err(f) => errors.push(Error::new(ErrorReason::FailedThread(f))),
-            _ => {}
+      if let Destination::File(ref output) = destination {
+         if let Some(length) = length {
+            self.check_space(output, length, parallel, scratch)?;
+         }
+         if scratch {
+            // store the URL we were given rather than the resolved one, as the latter might have
+            // expired by the time the download is resumed
+            if let Err(f) = self.create_download_config(output, url, parallel) {
+               self.output.error(&f);  // continue, but let the user know that they can't stop the download
+            }
          }
       }
 
-      if errors.len() > 0 {
-         Err(Error::new(ErrorReason::Multiple(errors)))
-      } else {
-         self.output.info("merging parts... ");
-         let result = self.merge_parts(parallel, output.as_ref());
-         self.output.info("finished merging");
-         match result {
-            Ok(()) => self.delete_download_config(output),
-            err => err
+      let reassembler = match destination {
+         Destination::Stdout => {
+            let memory_limit = STREAM_MEMORY_LIMIT / parallel as usize;
+            Some(Reassembler::new(io::stdout(), parallel as usize, memory_limit))
+         }
+         _ => None
+      };
+      let preallocate = self.config.preallocate;
+      let mut preallocated = true;
+      let writers = (0..parallel).map(|i| match destination {
+         Destination::File(ref output) => {
+            let opened = open_part(output, i, scratch).and_then(|(file, filelen)| {
+               if let (true, Some(length)) = (preallocate, length) {
+                  let (start, end) = part_bounds(i, parallel, length);
+                  let left = (end - start).saturating_sub(filelen);
+                  preallocated &= file.preallocate(left)?;
+               }
+               Ok((file, filelen))
+            });
+            match opened {
+               Ok((file, filelen)) => Ok((Box::new(file) as Box<dyn Write>, filelen)),
+               Err(f) => Err(Error::new(ErrorReason::IO(f)).in_part(i, resolved_url.clone()))
+            }
+         }
+         Destination::Stdout => match reassembler {
+            Some(ref reassembler) => {
+               Ok((Box::new(reassembler.part(i as usize)) as Box<dyn Write>, 0))
+            }
+            None => unreachable!()
+         },
+         Destination::Sink(ref sink) => {
+            let offset = length.map_or(0, |length| part_bounds(i, parallel, length).0);
+            Ok((Box::new(SeekWriter::new(sink.clone(), offset)) as Box<dyn Write>, 0))
          }
+      }).collect::<error::Result<Vec<(Box<dyn Write + 'a>, u64)>>>()?;
+      if preallocate && !preallocated {
+         self.output.warn("disk space cannot be reserved on this platform or filesystem");
       }
-   }
-
-   fn download_callback<W: Write>(part: u64,
-                                  mut pb: ProgressBar<W>,
-                                  client: Arc<Client>,
-                                  url: Url,
-                                  output: PathBuf,
-                                  length: Option<u64>,
-                                  parallel: u64,
-                                  config: DownloaderConfig,
-                                  scratch: bool) -> error::Result<()> {
-      pb.message("Waiting  : ");
+      let resumed = writers.iter().map(|&(_, filelen)| filelen).sum();
 
-      let (mut file, filelen) = if scratch {
-         (FilePart::create(&output, part), 0)
+      let style = self.output.progress_style();
+      let bars = if style == ProgressStyle::Hidden {
+         None
       } else {
-         let file = FilePart::load_or_create(&output, part);
-         let len = match file.metadata() {
-            Ok(data) => data.len(),
-            Err(/*f*/_) => {
-               //self.output.error(&format!("{}", f));
-               //self.output.warn("downloading from byte 0");
-               0
+         let (name, labels) = match destination {
+            Destination::File(ref output) => {
+               (output.display().to_string(),
+                (0..parallel).map(|i| format!("{}.part{}", output.display(), i)).collect())
             }
+            _ => (resolved_url.to_string(), (0..parallel).map(|i| format!("part {}", i)).collect())
          };
-         (file, len)
+         // when the file itself goes to stdout, the progress bars go to stderr
+         Some(match destination {
+            Destination::Stdout => ProgressBars::new(Box::new(io::stderr()), name, labels, style),
+            _ => ProgressBars::new(Box::new(io::stdout()), name, labels, style)
+         })
       };
+      let progress = Rc::new(RefCell::new(Progress {
+         bars,
+         output: &mut self.output,
+         listeners: &mut self.listeners
+      }));
+      progress.borrow_mut().emit(ProgressEvent::Started { length, parts: parallel, resumed });
 
-      let mut request = client.get(url).unwrap();
-      if let Some(length) = length {
-         let section = length / parallel;
-         if section == filelen || (part + 1 == parallel && length - section * part == filelen) {
-            // FIXME: does not print correctly when the program is restarted after an interrupted
-            //        download
-            pb.finish_print(&format!("Completed: {}.part{}", output.display(), part));
-            return Ok(());
-         }
-         let from = filelen + part * section;
-         let to = if part + 1 == parallel {
-            length
-         } else {
-            (part + 1) * section
-         } - 1;
-         request.header(Range::Bytes(vec![ByteRangeSpec::FromTo(from, to)]));
-      }
+      let mut parts: Vec<StartPart> = vec![];
+      let meter = Rc::new(RefCell::new(Throughput::new()));
 
-      if let Some(username) = config.username {
-         request.header(Authorization(Basic {
-             username: username,
-             password: config.password,
+      for (i, (writer, filelen)) in (0u64..).zip(writers) {
+         let progress = progress.clone();
+         let client = engine.client.clone();
+         let url = resolved_url.clone();
+         let config = part_config.clone();
+         let meter = meter.clone();
+         let reassembler = reassembler.clone();
+         // a part the server turned away has not written anything, so it keeps its writer for
+         // the next attempt
+         let writer = Rc::new(RefCell::new(Some(writer)));
+         // parts are only created once they get to run, as creating one sends its request
+         parts.push(Rc::new(move || {
+            let part = download_part(i,
+                                     progress.clone(),
+                                     &client,
+                                     url.clone(),
+                                     writer.clone(),
+                                     filelen,
+                                     length,
+                                     parallel,
+                                     &config,
+                                     meter.clone());
+            match reassembler {
+               Some(ref reassembler) => {
+                  let reassembler = reassembler.clone();
+                  let url = url.clone();
+                  Box::new(part.and_then(move |report| {
+                     match reassembler.finish(i as usize) {
+                        Ok(()) => Ok(report),
+                        Err(f) => Err(Error::new(ErrorReason::IO(f)).in_part(i, url))
+                     }
+                  }))
+               }
+               None => part
+            }
          }));
       }
 
-      let part = part as usize;
-      let result = match request.send() {
-         Ok(mut resp) => {
-            pb.message("Connected: ");
-            // FIXME: is this right/all?
-            if resp.status() == StatusCode::Ok || resp.status() == StatusCode::PartialContent {
-               let &ContentLength(length) = resp.headers().get()
-                                                          .unwrap_or(&ContentLength(u64::MAX));
-               pb.total = length;
-               // TODO: check accept-ranges or whatever
-               let mut buffer: [u8; 8192] = unsafe { mem::uninitialized() };
-               let mut downloaded = 0;
-               while downloaded < length {
-                  match resp.read(&mut buffer) {
-                     Ok(n) => {
-                        if n == 0 {
-                           break;
-                        } else {
-                           downloaded += n as u64;
-                           file.write_all(&buffer[0..n]).unwrap();
-                           pb.add(n as u64);
-                        }
-                     }
-                     Err(f) => return Err(Error::new(ErrorReason::IO(f)))
-                  }
-                  pb.tick();
-               }
-               pb.finish_print(&format!("Completed: {}.part{}", output.display(), part));
-               Ok(())
-            } else {
-               pb.finish_print(&format!("Failed   : {}.part{}", output.display(), part));
-               Err(Error::new(ErrorReason::HttpErrorCode(resp.status())))
+      let mut concurrency = if self.config.adaptive {
+         Concurrency::adaptive(parallel as usize)
+      } else {
+         Concurrency::fixed(parallel as usize)
+      };
+      let host = host_key(&resolved_url);
+      if let Some(&limit) = engine.host_limits.get(&host) {
+         if (limit as u64) < parallel {
+            progress.borrow_mut().output.verbose(&format!("{} pushed back before, using at most {} \
+                                                           connections",
+                                                          host,
+                                                          limit));
+         }
+         concurrency.cap(limit);
+      }
+      let schedule_progress = progress.clone();
+      let events = Box::new(move |event| {
+         let mut progress = schedule_progress.borrow_mut();
+         match event {
+            ScheduleEvent::Changed(change) => progress.output.verbose(&change),
+            ScheduleEvent::Retry { part, delay } => {
+               progress.output.warn(&format!("server is busy, requesting part {} again in {} \
+                                              seconds",
+                                             part,
+                                             delay.as_secs()));
+               progress.emit(ProgressEvent::PartRetry { part: part as u64 });
+            }
+            ScheduleEvent::GaveUp { part } => {
+               progress.emit(ProgressEvent::PartFinished { part: part as u64, succeeded: false });
             }
          }
-         Err(f) => {
-            pb.finish_print(&format!("Failed   : {}.part{}", output.display(), part));
-            Err(Error::new(ErrorReason::FailedRequest(f)))
+      });
+      let mut scheduler = Scheduler::new(parts,
+                                         concurrency,
+                                         meter.clone(),
+                                         engine.core.handle(),
+                                         events);
+      let results = engine.core.run(&mut scheduler).unwrap_or_default();
+      if let Some(limit) = scheduler.concurrency().accepted() {
+         engine.host_limits.insert(host, limit);
+      }
+      let retries = scheduler.retries();
+      drop(scheduler);
+      // the parts are done with the progress, which releases the output manager and listeners
+      let bars = match Rc::try_unwrap(progress) {
+         Ok(progress) => progress.into_inner().bars,
+         Err(_) => unreachable!()
+      };
+      let result = match bars {
+         Some(bars) => bars.join(),
+         None => Ok(())
+      }.and_then(|()| self.complete(destination, parallel, results));
+      self.emit(ProgressEvent::Done { succeeded: result.is_ok() });
+      let (path, part_reports) = result?;
+
+      let duration = start.elapsed();
+      let bytes_transferred = part_reports.iter().map(|part| part.bytes_transferred).sum();
+      let average_throughput = if duration > Duration::from_secs(0) {
+         bytes_transferred as f64 / duration.as_secs_f64()
+      } else {
+         0.0
+      };
+      let peak_throughput = meter.borrow().peak(average_throughput);
+      Ok(DownloadReport {
+         path,
+         url: resolved_url,
+         protocol,
+         length,
+         bytes_transferred,
+         bytes_reused: part_reports.iter().map(|part| part.bytes_reused).sum(),
+         duration,
+         average_throughput,
+         peak_throughput,
+         retries,
+         skipped: false,
+         headers,
+         // nothing is verified yet
+         checksum: None,
+         parts: part_reports
+      })
+   }
+
+   /// Checks the outcome of every part and puts the file in place if the parts were downloaded
+   /// to disk.  Returns where the file was saved, if anywhere, along with the reports of the parts.
+   fn complete(&mut self,
+               destination: Destination,
+               parallel: u64,
+               results: Vec<Result<PartReport, Error>>) -> error::Result<(Option<PathBuf>,
+                                                                          Vec<PartReport>)> {
+      let mut part_reports = vec![];
+      let mut errors = vec![];
+      for result in results {
+         match result {
+            Ok(report) => part_reports.push(report),
+            Err(f) => errors.push(f)
+         }
+      }
+      if errors.len() == 1 {
+         return Err(errors.remove(0));
+      } else if !errors.is_empty() {
+         return Err(Error::new(ErrorReason::Multiple(errors)));
+      }
+      let path = match destination {
+         Destination::File(output) => {
+            self.emit(ProgressEvent::Merging);
+            self.output.info("merging parts... ");
+            let result = self.finalize(parallel, &output);
+            self.output.info("finished merging");
+            result?;
+            self.delete_download_config(&output)?;
+            Some(output)
          }
+         // the reassembler has written everything out once the last part is finished
+         Destination::Stdout | Destination::Sink(_) => None
       };
+      Ok((path, part_reports))
+   }
 
-      result
+   fn emit(&mut self, event: ProgressEvent) {
+      self.output.progress(&event);
+      for listener in &mut self.listeners {
+         listener.event(&event);
+      }
+   }
+
+   /// Merges the parts into a temporary file next to the output and only then renames it into
+   /// place, so an interrupted merge never leaves a truncated file at the output path.  The parts
+   /// are deleted once the output is complete.
+   fn finalize<P: AsRef<Path>>(&mut self, parallel: u64, output_path: P) -> error::Result<()> {
+      let output_path = output_path.as_ref();
+      let temp_path = util::add_suffix(output_path, TEMP_EXTENSION);
+      if let Err(f) = self.merge_parts(parallel, output_path, &temp_path) {
+         let _ = fs::remove_file(&temp_path);
+         return Err(f);
+      }
+
+      if self.config.overwrite == OverwritePolicy::Backup {
+         match util::backup_file(output_path) {
+            Ok(Some(backup)) => {
+               self.output.info(&format!("moved existing {} to {}",
+                                         output_path.display(),
+                                         backup.display()));
+            }
+            Ok(None) => {}
+            Err(f) => return Err(Error::new(ErrorReason::IO(f)))
+         }
+      }
+      if let Err(f) = fs::rename(&temp_path, output_path) {
+         return Err(Error::new(ErrorReason::IO(f)));
+      }
+
+      for i in 0..parallel {
+         if let Err(f) = FilePart::open(output_path, i).and_then(FilePart::delete) {
+            return Err(Error::new(ErrorReason::IO(f)));
+         }
+      }
+      Ok(())
    }
 
-   fn merge_parts<P: AsRef<Path>>(&self,
-                                  parallel: u64,
-                                  output_path: P) -> error::Result<()> {
+   fn merge_parts(&self, parallel: u64, output_path: &Path, temp_path: &Path) -> error::Result<()> {
       let file = match OpenOptions::new().write(true)
                                          .create(true)
-                                         .open(output_path.as_ref()) {
+                                         .truncate(true)
+                                         .open(temp_path) {
          Ok(m) => m,
-         Err(f) => return Err(Error::new(ErrorReason::IO(f)))
+         Err(f) => return Err(Error::new(ErrorReason::IO(FileError::wrap(f, "create", temp_path))))
       };
 
       let mut output = BufWriter::new(file);
       let mut total_size = 0;
       for i in 0..parallel {
-         let mut infile = FilePart::open(output_path.as_ref(), i);
-         match io::copy(&mut infile, &mut output) {
-            Ok(n) => total_size += n as u64,
+         let copied = FilePart::open(output_path, i).and_then(|mut infile| {
+            // only errors of the output file lack context, as FilePart adds it to its own
+            io::copy(&mut infile, &mut output).map_err(|f| FileError::wrap(f, "write to", temp_path))
+         });
+         match copied {
+            Ok(n) => total_size += n,
             Err(f) => return Err(Error::new(ErrorReason::IO(f)))
          }
-         infile.delete();
       }
-      output.into_inner().unwrap().set_len(total_size).unwrap();
+      let file = match output.into_inner() {
+         Ok(m) => m,
+         Err(f) => {
+            return Err(Error::new(ErrorReason::IO(FileError::wrap(f.into_error(),
+                                                                  "write to",
+                                                                  temp_path))))
+         }
+      };
+      // make sure the data is on disk before the file is renamed into place
+      if let Err(f) = file.set_len(total_size).and_then(|_| file.sync_all()) {
+         return Err(Error::new(ErrorReason::IO(FileError::wrap(f, "write to", temp_path))));
+      }
+
+      Ok(())
+   }
 
+   /// Fails if the filesystem of `output` does not have room for the rest of the parts as well
+   /// as for the copy of the whole file they are merged into.
+   fn check_space(&mut self,
+                  output: &Path,
+                  length: u64,
+                  parallel: u64,
+                  scratch: bool) -> error::Result<()> {
+      let dir = match output.parent() {
+         Some(dir) if dir != Path::new("") => dir,
+         _ => Path::new(".")
+      };
+      let available = match util::available_space(dir) {
+         Ok(Some(available)) => available,
+         Ok(None) => return Ok(()),
+         Err(f) => {
+            self.output.warn(&format!("could not check the free space in {}: {}", dir.display(), f));
+            return Ok(());
+         }
+      };
+      let downloaded: u64 = if scratch {
+         0
+      } else {
+         (0..parallel).filter_map(|i| fs::metadata(FilePart::add_part_extension(output, i)).ok())
+                      .map(|data| data.len())
+                      .sum()
+      };
+      let needed = length.saturating_sub(downloaded) + length;
+      self.output.verbose(&format!("{} bytes needed in {}, {} available",
+                                   needed,
+                                   dir.display(),
+                                   available));
+      if needed > available {
+         return Err(Error::new(ErrorReason::NotEnoughSpace(dir.to_path_buf(), needed, available)));
+      }
       Ok(())
    }
 
@@ -358,12 +941,14 @@ impl<T: OutputManager> Downloader<T> {
          parallel: parallel
       };
 
-      match File::create(util::add_path_extension(output, "toml")) {
-         Ok(mut file) => {
-            file.write_all(toml::to_string(&config).unwrap().as_bytes()).unwrap();
-            Ok(())
-         }
-         Err(f) => Err(format!("{}", f))
+      let data = match toml::to_string(&config) {
+         Ok(m) => m,
+         Err(f) => return Err(format!("{}", f))
+      };
+      let path = util::add_path_extension(output, "toml");
+      match File::create(&path).and_then(|mut file| file.write_all(data.as_bytes())) {
+         Ok(()) => Ok(()),
+         Err(f) => Err(format!("{}", FileError::wrap(f, "write", &path)))
       }
    }
 
@@ -373,28 +958,468 @@ impl<T: OutputManager> Downloader<T> {
          Err(f) => Err(Error::new(ErrorReason::IO(f)))
       }
    }
+}
 
-   fn get_length(&self, client: Arc<Client>, url: Url) -> Option<u64> {
-      let mut request = client.get(url).unwrap();
-      if let Some(ref username) = self.config.username {
-         request.header(Authorization(Basic {
-            username: username.to_owned(),
-            password: self.config.password.to_owned(),
-         }));
+/// Opens the file for part `part` of `output`.  Unless starting from scratch, data downloaded
+/// earlier is kept and its length returned along with the file.
+fn open_part(output: &Path, part: u64, scratch: bool) -> io::Result<(FilePart, u64)> {
+   if scratch {
+      FilePart::create(output, part).map(|file| (file, 0))
+   } else {
+      let file = FilePart::load_or_create(output, part)?;
+      // the file is appended to, so its length must be known to request the rest of the part
+      let len = file.metadata()?.len();
+      Ok((file, len))
+   }
+}
+
+/// The bytes part `part` of a file of `length` bytes receives, as its first byte and the byte
+/// after its last.  The last part also receives what is left over when dividing the file evenly.
+fn part_bounds(part: u64, parallel: u64, length: u64) -> (u64, u64) {
+   let section = length / parallel;
+   let end = if part + 1 == parallel {
+      length
+   } else {
+      (part + 1) * section
+   };
+   (part * section, end)
+}
+
+/// The number of parts to split a new download of `length` bytes into: `parallel`, or the number
+/// picked from `latency` in automatic mode, but no more than allow parts of at least the minimum
+/// size and no more than the host's connection limit, which counts connections rather than parts
+/// if the parts are fetched as `http2` streams.  Every part gets at least one byte, so only an
+/// empty file is downloaded as a single empty part.
+fn plan_parts(config: &DownloaderConfig,
+              parallel: u64,
+              length: u64,
+              latency: Duration,
+              http2: bool) -> u64 {
+   let wanted = if config.auto_parallel {
+      // a single connection's throughput is limited by how long the server takes to acknowledge
+      // the data, so slower servers are worth more connections
+      match latency.as_millis() {
+         0..=49 => 4,
+         50..=199 => 8,
+         _ => MAX_AUTO_PARALLEL
+      }
+   } else {
+      parallel
+   };
+   let mut parts = match length.checked_div(config.min_part_size) {
+      Some(fitting) => wanted.min(fitting),
+      None => wanted
+   };
+   if let Some(max) = config.max_connections_per_host {
+      let max = match config.max_streams_per_connection {
+         _ if !http2 => max,
+         Some(streams) => max.saturating_mul(streams),
+         None => u64::MAX
+      };
+      parts = parts.min(max);
+   }
+   parts.min(length).max(1)
+}
+
+/// Downloads a single part of the file at `url` into its part file.  File writes are small and
+/// buffered by the OS, so they are done directly on the event loop.
+fn download_part<'a, T: OutputManager>(part: u64,
+                                       progress: Rc<RefCell<Progress<'a, T>>>,
+                                       client: &Client,
+                                       url: Url,
+                                       file: Rc<RefCell<Option<Box<dyn Write + 'a>>>>,
+                                       filelen: u64,
+                                       length: Option<u64>,
+                                       parallel: u64,
+                                       config: &DownloaderConfig,
+                                       meter: Rc<RefCell<Throughput>>)
+                                       -> Box<dyn Future<Item = PartReport, Error = Error> + 'a> {
+   let start = Instant::now();
+   let mut report = PartReport {
+      index: part,
+      range: None,
+      bytes_transferred: 0,
+      bytes_reused: filelen,
+      duration: Duration::from_secs(0)
+   };
+   let mut range = None;
+   if let Some(length) = length {
+      let (start, end) = part_bounds(part, parallel, length);
+      // only an empty file has an empty part, which has no range to request
+      if end > start {
+         report.range = Some((start, end - 1));
+      }
+      if end - start == filelen {
+         let mut progress = progress.borrow_mut();
+         progress.output.verbose(&format!("part {}: all {} bytes already downloaded",
+                                          part,
+                                          filelen));
+         progress.emit(ProgressEvent::PartStarted { part, length: Some(0), resumed: filelen });
+         progress.emit(ProgressEvent::PartFinished { part, succeeded: true });
+         return Box::new(future::ok(report));
+      }
+      let from = start + filelen;
+      let to = end - 1;
+      let plan = if filelen > 0 {
+         format!("part {}: requesting bytes {}-{} (resuming after {} bytes)", part, from, to, filelen)
+      } else {
+         format!("part {}: requesting bytes {}-{}", part, from, to)
+      };
+      progress.borrow_mut().output.verbose(&plan);
+      range = Some(ByteRangeSpec::FromTo(from, to));
+   } else {
+      progress.borrow_mut().output.verbose(&format!("part {}: requesting the whole file", part));
+   }
+
+   let events = progress.clone();
+   let ranged = range.is_some();
+   let trace_progress = progress.clone();
+   let trace: Trace<'a> = Rc::new(move |level, msg| {
+      trace(&mut *trace_progress.borrow_mut().output, level, msg)
+   });
+   let part_url = url.clone();
+   let transfer = send_request(client, config, url, range, trace).and_then(move |(_, resp)| {
+      if concurrency::is_back_pressure(resp.status()) {
+         let reason = ErrorReason::ServerBusy(resp.status(), retry_after(resp.headers()));
+         return Either::A(future::err(Error::new(reason)));
+      }
+      // FIXME: is this right/all?
+      if resp.status() != StatusCode::Ok && resp.status() != StatusCode::PartialContent {
+         return Either::A(future::err(Error::new(ErrorReason::HttpErrorCode(resp.status()))));
+      }
+      // a compressed byte range cannot be decoded on its own
+      let encodings = compression::content_encoding(resp.headers());
+      if ranged && !encodings.is_empty() {
+         let names: Vec<String> = encodings.iter().map(|encoding| encoding.to_string()).collect();
+         let reason = ErrorReason::UnexpectedEncoding(names.join(", "));
+         return Either::A(future::err(Error::new(reason)));
+      }
+      let file = match file.borrow_mut().take() {
+         Some(file) => file,
+         None => unreachable!()
+      };
+      let output = match compression::decoder(&encodings, file) {
+         Ok(m) => m,
+         Err(f) => return Either::A(future::err(f))
+      };
+      let length = resp.headers().get().map(|&ContentLength(length)| length);
+      events.borrow_mut().emit(ProgressEvent::PartStarted { part, length, resumed: filelen });
+      // TODO: check accept-ranges or whatever
+      let body = resp.body();
+      Either::B(body.map_err(|f| Error::new(ErrorReason::FailedRequest(f)))
+                    .fold((output, 0), move |(mut output, transferred), chunk| {
+         if let Err(f) = output.write_all(&chunk) {
+            return Err(compression::decoder_error(f));
+         }
+         events.borrow_mut().emit(ProgressEvent::BytesWritten { part, n: chunk.len() as u64 });
+         meter.borrow_mut().add(chunk.len() as u64);
+         Ok((output, transferred + chunk.len() as u64))
+      }).and_then(|(mut output, transferred)| match output.finish() {
+         Ok(()) => Ok(transferred),
+         Err(f) => Err(compression::decoder_error(f))
+      }))
+   });
+
+   Box::new(transfer.then(move |result| {
+      let succeeded = result.is_ok();
+      let busy = result.as_ref().err().is_some_and(|f| {
+         matches!(*f.reason(), ErrorReason::ServerBusy(..))
+      });
+      // the scheduler decides whether a part the server turned away is finished
+      if !busy {
+         progress.borrow_mut().emit(ProgressEvent::PartFinished { part, succeeded });
+      }
+      match result {
+         Ok(transferred) => {
+            report.bytes_transferred = transferred;
+            report.duration = start.elapsed();
+            Ok(report)
+         }
+         Err(f) => Err(f.in_part(part, part_url))
       }
+   }))
+}
+
+/// Passes the details of requests on to the output manager.
+type Trace<'a> = Rc<dyn Fn(Verbosity, &str) + 'a>;
+
+fn tracer<'a, T: OutputManager>(output: &'a mut T) -> Trace<'a> {
+   let output = RefCell::new(output);
+   Rc::new(move |level, msg| trace(&mut **output.borrow_mut(), level, msg))
+}
+
+fn trace<T: OutputManager>(output: &mut T, level: Verbosity, msg: &str) {
+   match level {
+      Verbosity::Debug => output.debug(msg),
+      _ => output.verbose(msg)
+   }
+}
 
-      match request.send() {
-         Ok(resp) => {
-            if resp.status() == StatusCode::Ok {
+/// Sends the initial request for `url` to find out about the file.  If the request fails, the
+/// given URL is used and nothing else is known, so the parts can report the failure themselves.
+fn probe<'a>(client: &Client,
+             config: &DownloaderConfig,
+             url: Url,
+             trace: Trace<'a>) -> Box<dyn Future<Item = RemoteFile, Error = Error> + 'a> {
+   let start = Instant::now();
+   Box::new(send_request(client, config, url.clone(), None, trace).then(move |result| {
+      let latency = start.elapsed();
+      Ok(match result {
+         Ok((url, resp)) => {
+            let length = if resp.status() == StatusCode::Ok {
                match resp.headers().get() {
                   Some(&ContentLength(length)) => Some(length),
                   None => None
                }
             } else {
                None
+            };
+            let headers = ReportHeaders {
+               content_type: resp.headers().get::<ContentType>().map(ToString::to_string),
+               etag: resp.headers().get::<ETag>().map(ToString::to_string),
+               last_modified: resp.headers().get::<LastModified>().map(ToString::to_string)
+            };
+            RemoteFile {
+               length,
+               encoded: !compression::content_encoding(resp.headers()).is_empty(),
+               name: filename::resolve(resp.headers(), &url),
+               url,
+               headers,
+               protocol: Some(protocol_name(resp.version())),
+               latency
             }
          }
-         Err(_) => None
+         Err(_) => RemoteFile {
+            name: filename::resolve(&Headers::new(), &url),
+            url,
+            length: None,
+            encoded: false,
+            headers: ReportHeaders::default(),
+            protocol: None,
+            latency
+         }
+      })
+   }))
+}
+
+/// The name the version of HTTP a response was sent over is reported by.
+fn protocol_name(version: HttpVersion) -> String {
+   match version {
+      HttpVersion::H2 => HTTP2_NAME.to_string(),
+      version => version.to_string()
+   }
+}
+
+/// How long the server asked to wait before sending another request.
+fn retry_after(headers: &Headers) -> Option<Duration> {
+   match headers.get::<RetryAfter>() {
+      Some(&RetryAfter::Delay(delay)) => Some(delay),
+      Some(&RetryAfter::DateTime(date)) => {
+         Some(SystemTime::from(date).duration_since(SystemTime::now()).unwrap_or_default())
+      }
+      None => None
+   }
+}
+
+/// Sends a GET request for `url`, following redirects according to the configured policy.
+///
+/// Credentials are only attached while the request stays on the host it was originally sent to,
+/// so they are never leaked to e.g. a CDN on another domain.
+fn send_request<'a>(client: &Client,
+                    config: &DownloaderConfig,
+                    url: Url,
+                    range: Option<ByteRangeSpec>,
+                    trace: Trace<'a>) -> Box<dyn Future<Item = (Url, Response), Error = Error> + 'a> {
+   let client = client.clone();
+   let credentials = config.username.clone().map(|username| Basic {
+      username,
+      password: config.password.clone()
+   });
+   // only a whole file can be transferred compressed
+   let accept_encoding = compression::accept_encoding(config.compressed && range.is_none());
+   let max_redirects = config.max_redirects;
+   let allow_cross_scheme = config.allow_cross_scheme;
+   let origin = url.clone();
+
+   Box::new(future::loop_fn((url, 0), move |(url, redirects)| {
+      let mut request = match client.get(&url) {
+         Ok(m) => m,
+         Err(f) => return Either::A(future::err(f))
+      };
+      if let Some(ref range) = range {
+         request.headers_mut().set(Range::Bytes(vec![range.clone()]));
+      }
+      request.headers_mut().set(accept_encoding.clone());
+      if let Some(ref credentials) = credentials {
+         if forwards_credentials(&origin, &url) {
+            request.headers_mut().set(Authorization(credentials.clone()));
+         }
+      }
+      trace(Verbosity::Verbose, &format!("GET {}", url));
+      for header in request.headers().iter() {
+         // keep the passwords out of logs
+         if header.is::<Authorization<Basic>>()
+               || header.name().eq_ignore_ascii_case("Proxy-Authorization") {
+            trace(Verbosity::Debug, &format!("> {}: Basic <redacted>", header.name()));
+         } else {
+            trace(Verbosity::Debug, &format!("> {}: {}", header.name(), header.value_string()));
+         }
+      }
+
+      let trace = trace.clone();
+      Either::B(client.execute(request)
+                      .map_err(Error::failed_request)
+                      .and_then(move |resp| {
+         trace(Verbosity::Verbose, &format!("{} from {}", resp.status(), url));
+         for header in resp.headers().iter() {
+            trace(Verbosity::Debug, &format!("< {}: {}", header.name(), header.value_string()));
+         }
+         if !resp.status().is_redirection() {
+            return Ok(Loop::Break((url, resp)));
+         }
+         let next = match resp.headers().get::<Location>() {
+            Some(location) => match url.join(location) {
+               Ok(next) => next,
+               Err(f) => return Err(Error::new(ErrorReason::InvalidUrl(f)))
+            },
+            // e.g. 304 Not Modified, let the caller deal with it
+            None => return Ok(Loop::Break((url, resp)))
+         };
+
+         if redirects == max_redirects {
+            return Err(Error::new(ErrorReason::TooManyRedirects(max_redirects)));
+         }
+         let upgrade = url.scheme() == "http" && next.scheme() == "https";
+         if next.scheme() != url.scheme() && !upgrade && !allow_cross_scheme {
+            return Err(Error::new(ErrorReason::ForbiddenRedirect(next)));
+         }
+         Ok(Loop::Continue((next, redirects + 1)))
+      }))
+   }))
+}
+
+/// The host and port of `url`, which identify the server for its connection limit.
+fn host_key(url: &Url) -> String {
+   format!("{}:{}", url.host_str().unwrap_or(""), url.port_or_known_default().unwrap_or(0))
+}
+
+/// Whether credentials meant for `origin` may be sent along with a request to `target`.
+fn forwards_credentials(origin: &Url, target: &Url) -> bool {
+   let downgrade = origin.scheme() == "https" && target.scheme() != "https";
+   origin.host_str() == target.host_str()
+      && origin.port_or_known_default() == target.port_or_known_default()
+      && !downgrade
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn forwards(origin: &str, target: &str) -> bool {
+      forwards_credentials(&Url::parse(origin).unwrap(), &Url::parse(target).unwrap())
+   }
+
+   #[test]
+   fn forwards_credentials_to_the_same_origin() {
+      assert!(forwards("https://host/a", "https://host/b"));
+      assert!(forwards("https://host/a", "https://host:443/b"));
+      assert!(forwards("http://host/a", "http://host:80/b"));
+   }
+
+   #[test]
+   fn forwards_credentials_on_upgrade_to_https() {
+      // the default ports differ, so only an explicit port keeps the origin
+      assert!(!forwards("http://host/a", "https://host/b"));
+      assert!(forwards("http://host:8080/a", "https://host:8080/b"));
+   }
+
+   #[test]
+   fn keeps_credentials_from_other_hosts() {
+      assert!(!forwards("https://host/a", "https://other/b"));
+      assert!(!forwards("https://host/a", "https://sub.host/b"));
+      assert!(!forwards("https://host/a", "https://host:8443/b"));
+   }
+
+   #[test]
+   fn keeps_credentials_from_downgrades() {
+      assert!(!forwards("https://host/a", "http://host/b"));
+      assert!(!forwards("https://host:8080/a", "http://host:8080/b"));
+   }
+
+   fn planned(config: &DownloaderConfig, parallel: u64, length: u64) -> u64 {
+      plan_parts(config, parallel, length, Duration::from_millis(10), false)
+   }
+
+   #[test]
+   fn part_bounds_cover_the_file() {
+      assert_eq!(part_bounds(0, 3, 10), (0, 3));
+      assert_eq!(part_bounds(1, 3, 10), (3, 6));
+      // the last part receives the remainder
+      assert_eq!(part_bounds(2, 3, 10), (6, 10));
+      assert_eq!(part_bounds(0, 1, 10), (0, 10));
+      assert_eq!(part_bounds(0, 1, 0), (0, 0));
+   }
+
+   #[test]
+   fn plans_parts_of_at_least_the_minimum_size() {
+      let config = DownloaderConfig { min_part_size: 100, ..DownloaderConfig::default() };
+      assert_eq!(planned(&config, 4, 1000), 4);
+      assert_eq!(planned(&config, 4, 250), 2);
+      assert_eq!(planned(&config, 4, 99), 1);
+   }
+
+   #[test]
+   fn plans_no_more_parts_than_the_connection_limit() {
+      let config = DownloaderConfig {
+         min_part_size: 0,
+         max_connections_per_host: Some(2),
+         ..DownloaderConfig::default()
+      };
+      assert_eq!(planned(&config, 8, 1000), 2);
+   }
+
+   #[test]
+   fn limits_http2_connections_rather_than_streams() {
+      let mut config = DownloaderConfig {
+         min_part_size: 0,
+         max_connections_per_host: Some(2),
+         max_streams_per_connection: Some(3),
+         ..DownloaderConfig::default()
+      };
+      let latency = Duration::from_millis(10);
+      assert_eq!(plan_parts(&config, 8, 1000, latency, true), 6);
+      config.max_streams_per_connection = None;
+      assert_eq!(plan_parts(&config, 8, 1000, latency, true), 8);
+   }
+
+   #[test]
+   fn plans_a_single_part_for_an_empty_file() {
+      let config = DownloaderConfig::default();
+      assert_eq!(planned(&config, 4, 0), 1);
+      let config = DownloaderConfig { min_part_size: 0, ..DownloaderConfig::default() };
+      assert_eq!(planned(&config, 4, 0), 1);
+   }
+
+   #[test]
+   fn plans_no_empty_parts_without_a_minimum_size() {
+      let config = DownloaderConfig { min_part_size: 0, ..DownloaderConfig::default() };
+      assert_eq!(planned(&config, 8, 3), 3);
+      for part in 0..3 {
+         let (start, end) = part_bounds(part, 3, 3);
+         assert!(end > start);
       }
    }
+
+   #[test]
+   fn plans_parts_from_latency_in_automatic_mode() {
+      let config = DownloaderConfig {
+         min_part_size: 0,
+         auto_parallel: true,
+         ..DownloaderConfig::default()
+      };
+      let length = 1 << 30;
+      assert_eq!(plan_parts(&config, 1, length, Duration::from_millis(10), false), 4);
+      assert_eq!(plan_parts(&config, 1, length, Duration::from_millis(100), false), 8);
+      assert_eq!(plan_parts(&config, 1, length, Duration::from_millis(500), false), MAX_AUTO_PARALLEL);
+   }
 }
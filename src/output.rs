@@ -6,44 +6,324 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use term::{self, StdoutTerminal, StderrTerminal};
+use std::cmp;
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use term::{self, StdoutTerminal, StderrTerminal, Terminal};
+
+use progress::{self, ProgressEvent, ProgressStyle};
+use report::DownloadReport;
+
+/// How often `JsonOutputManager` reports the progress of a download.
+const JSON_PROGRESS_INTERVAL: u64 = 1000;
 
 pub trait OutputManager {
    fn info(&mut self, msg: &str);
    fn warn(&mut self, msg: &str);
    fn error(&mut self, msg: &str);
+
+   /// Details that are only of interest when looking into a problem, such as the byte ranges
+   /// requested by the parts.
+   fn verbose(&mut self, _msg: &str) {}
+
+   /// Even more detail than `verbose()`, such as the headers of every request and response.
+   fn debug(&mut self, _msg: &str) {}
+
+   /// How to draw the progress of a download on the terminal.  Output managers that report
+   /// progress themselves through `progress()` should hide it.
+   fn progress_style(&self) -> ProgressStyle {
+      ProgressStyle::Full
+   }
+
+   /// Receives the progress events of every download.
+   fn progress(&mut self, _event: &ProgressEvent) {}
+
+   /// Receives the report of every finished download.
+   fn report(&mut self, _report: &DownloadReport) {}
+
+   /// Called with `true` before a download is written to stdout and with `false` once it is
+   /// done.  Nothing else may be printed to stdout in between.
+   fn stdout_taken(&mut self, _taken: bool) {}
+}
+
+/// How much `StdOutputManager` prints.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+   /// Only errors.
+   Quiet,
+   #[default]
+   Normal,
+   /// Also the byte range every part asks for and the status of every request.
+   Verbose,
+   /// Also the headers of every request and response.
+   Debug
 }
 
 pub struct StdOutputManager {
-   stdout: Box<StdoutTerminal>,
-   stderr: Box<StderrTerminal>
+   // None if the stream is not a terminal or its capabilities are unknown (e.g. in a container
+   // without terminfo), in which case messages are printed as plain text
+   stdout: Option<Box<StdoutTerminal>>,
+   stderr: Option<Box<StderrTerminal>>,
+   stderr_only: bool,
+   /// Whether a download is being written to stdout right now.
+   stdout_taken: bool,
+   verbosity: Verbosity,
+   compact: bool,
+   log: Option<File>
 }
 
 impl StdOutputManager {
    pub fn new() -> StdOutputManager {
       StdOutputManager {
-         stdout: term::stdout().unwrap(),
-         stderr: term::stderr().unwrap()
+         stdout: if io::stdout().is_terminal() { term::stdout() } else { None },
+         stderr: if io::stderr().is_terminal() { term::stderr() } else { None },
+         stderr_only: false,
+         stdout_taken: false,
+         verbosity: Verbosity::Normal,
+         compact: false,
+         log: None
+      }
+   }
+
+   /// Creates an output manager that leaves stdout alone, for when the download itself is
+   /// written there.
+   pub fn stderr_only() -> StdOutputManager {
+      StdOutputManager {
+         stderr_only: true,
+         ..StdOutputManager::new()
+      }
+   }
+
+   pub fn set_verbosity(&mut self, verbosity: Verbosity) {
+      self.verbosity = verbosity;
+   }
+
+   /// Always shows a single line of progress, rather than only on a narrow terminal.
+   pub fn set_compact_progress(&mut self, compact: bool) {
+      self.compact = compact;
+   }
+
+   /// Also writes the messages to `file` as plain text.  The file gets every message at least
+   /// up to `Verbosity::Normal`, even if less is printed.
+   pub fn set_log_file(&mut self, file: File) {
+      self.log = Some(file);
+   }
+
+   fn message(&mut self, level: Verbosity, color: term::color::Color, prefix: &str, msg: &str) {
+      let line = format!("{}: {}", prefix, msg);
+      if let Some(ref mut log) = self.log {
+         if level <= cmp::max(self.verbosity, Verbosity::Normal) {
+            let _ = writeln!(log, "{}", line);
+         }
+      }
+      if level > self.verbosity {
+         return;
+      }
+      // errors always go to stderr
+      if self.stderr_only || self.stdout_taken || level == Verbosity::Quiet {
+         write_line(&mut self.stderr, &mut io::stderr(), color, &line);
+      } else {
+         write_line(&mut self.stdout, &mut io::stdout(), color, &line);
       }
    }
 }
 
 impl OutputManager for StdOutputManager {
    fn info(&mut self, msg: &str) {
-      self.stdout.fg(term::color::GREEN).unwrap();
-      writeln!(self.stdout, "info: {}", msg).unwrap();
-      self.stdout.reset().unwrap();
+      self.message(Verbosity::Normal, term::color::GREEN, "info", msg);
    }
 
    fn warn(&mut self, msg: &str) {
-      self.stdout.fg(term::color::YELLOW).unwrap();
-      writeln!(self.stdout, "warn: {}", msg).unwrap();
-      self.stdout.reset().unwrap();
+      self.message(Verbosity::Normal, term::color::YELLOW, "warn", msg);
    }
 
    fn error(&mut self, msg: &str) {
-      self.stderr.fg(term::color::RED).unwrap();
-      writeln!(self.stderr, "error: {}", msg).unwrap();
-      self.stderr.reset().unwrap();
+      self.message(Verbosity::Quiet, term::color::RED, "error", msg);
+   }
+
+   fn verbose(&mut self, msg: &str) {
+      self.message(Verbosity::Verbose, term::color::CYAN, "verbose", msg);
+   }
+
+   fn debug(&mut self, msg: &str) {
+      self.message(Verbosity::Debug, term::color::CYAN, "debug", msg);
+   }
+
+   /// Progress is only drawn on a terminal, as it is just noise in a file or a CI log.
+   fn progress_style(&self) -> ProgressStyle {
+      let (terminal, fd) = if self.stderr_only || self.stdout_taken {
+         (io::stderr().is_terminal(), 2)
+      } else {
+         (io::stdout().is_terminal(), 1)
+      };
+      if self.verbosity == Verbosity::Quiet || !terminal {
+         ProgressStyle::Hidden
+      } else if self.compact {
+         ProgressStyle::Compact
+      } else {
+         progress::style_for_width(progress::terminal_width(fd))
+      }
+   }
+
+   fn stdout_taken(&mut self, taken: bool) {
+      self.stdout_taken = taken;
    }
-}
\ No newline at end of file
+}
+
+/// Writes `line` to `terminal` in `color`, or to `plain` if colours cannot be used.
+fn write_line<T: Terminal + ?Sized>(terminal: &mut Option<Box<T>>,
+                                    plain: &mut dyn Write,
+                                    color: term::color::Color,
+                                    line: &str) {
+   // there is nowhere left to report a failure to print a message
+   match *terminal {
+      Some(ref mut terminal) => {
+         let _ = terminal.fg(color);
+         let _ = writeln!(terminal, "{}", line);
+         let _ = terminal.reset();
+      }
+      None => {
+         let _ = writeln!(plain, "{}", line);
+      }
+   }
+}
+
+/// Prints every message and event as a JSON object on a line of its own to stdout (or to stderr
+/// while a download is written to stdout), for programs that wrap rget.  Progress is printed at
+/// most once per second rather than for every chunk.
+#[derive(Default)]
+pub struct JsonOutputManager {
+   stdout_taken: bool,
+   length: Option<u64>,
+   /// Bytes of the file downloaded so far, including those from an earlier run.
+   written: u64,
+   last_progress: Option<Instant>
+}
+
+impl JsonOutputManager {
+   pub fn new() -> JsonOutputManager {
+      JsonOutputManager::default()
+   }
+
+   fn print(&self, value: Value) {
+      // there is nowhere left to report a failure to print
+      if self.stdout_taken {
+         let _ = writeln!(io::stderr(), "{}", value);
+      } else {
+         let stdout = io::stdout();
+         let mut stdout = stdout.lock();
+         let _ = writeln!(stdout, "{}", value).and_then(|()| stdout.flush());
+      }
+   }
+
+   fn print_progress(&mut self) {
+      self.last_progress = Some(Instant::now());
+      self.print(json!({
+         "type": "progress",
+         "bytes": self.written,
+         "length": self.length
+      }));
+   }
+}
+
+impl OutputManager for JsonOutputManager {
+   fn info(&mut self, msg: &str) {
+      self.print(json!({ "type": "info", "message": msg }));
+   }
+
+   fn warn(&mut self, msg: &str) {
+      self.print(json!({ "type": "warning", "message": msg }));
+   }
+
+   fn error(&mut self, msg: &str) {
+      self.print(json!({ "type": "error", "message": msg }));
+   }
+
+   fn progress_style(&self) -> ProgressStyle {
+      ProgressStyle::Hidden
+   }
+
+   fn progress(&mut self, event: &ProgressEvent) {
+      match *event {
+         ProgressEvent::Started { length, parts, resumed } => {
+            self.length = length;
+            self.written = resumed;
+            self.last_progress = Some(Instant::now());
+            self.print(json!({
+               "type": "started",
+               "length": length,
+               "parts": parts,
+               "resumed": resumed
+            }));
+         }
+         ProgressEvent::PartStarted { part, length, resumed } => {
+            self.print(json!({
+               "type": "part_started",
+               "part": part,
+               "length": length,
+               "resumed": resumed
+            }));
+         }
+         ProgressEvent::BytesWritten { n, .. } => {
+            self.written += n;
+            let due = self.last_progress.is_none_or(|last| {
+               last.elapsed() >= Duration::from_millis(JSON_PROGRESS_INTERVAL)
+            });
+            if due {
+               self.print_progress();
+            }
+         }
+         ProgressEvent::PartRetry { part } => {
+            self.print(json!({ "type": "part_retry", "part": part }));
+         }
+         ProgressEvent::PartFinished { part, succeeded } => {
+            self.print(json!({ "type": "part_finished", "part": part, "succeeded": succeeded }));
+         }
+         ProgressEvent::Merging => self.print(json!({ "type": "merging" })),
+         ProgressEvent::Done { succeeded } => {
+            self.print_progress();
+            self.print(json!({ "type": "done", "succeeded": succeeded }));
+         }
+      }
+   }
+
+   fn report(&mut self, report: &DownloadReport) {
+      let parts: Vec<Value> = report.parts.iter().map(|part| {
+         json!({
+            "index": part.index,
+            "range": part.range.map(|(from, to)| vec![from, to]),
+            "bytes_transferred": part.bytes_transferred,
+            "bytes_reused": part.bytes_reused,
+            "duration": part.duration.as_secs_f64()
+         })
+      }).collect();
+      self.print(json!({
+         "type": "report",
+         "path": report.path.as_ref().map(|path| path.display().to_string()),
+         "url": report.url.as_str(),
+         "protocol": report.protocol,
+         "length": report.length,
+         "bytes_transferred": report.bytes_transferred,
+         "bytes_reused": report.bytes_reused,
+         "duration": report.duration.as_secs_f64(),
+         "average_throughput": report.average_throughput,
+         "peak_throughput": report.peak_throughput,
+         "retries": report.retries,
+         "skipped": report.skipped,
+         "headers": {
+            "content_type": report.headers.content_type,
+            "etag": report.headers.etag,
+            "last_modified": report.headers.last_modified
+         },
+         "checksum": report.checksum,
+         "parts": parts
+      }));
+   }
+
+   fn stdout_taken(&mut self, taken: bool) {
+      self.stdout_taken = taken;
+   }
+}
@@ -9,6 +9,7 @@
 use std::fs::{self, File, OpenOptions, Metadata};
 use std::path::{Path, PathBuf};
 use std::io::{self, Write, Read, SeekFrom, Seek};
+use error::FileError;
 use util;
 //use std::io::{BufReader, BufWriter};
 
@@ -18,61 +19,72 @@ pub struct FilePart {
 }
 
 impl FilePart {
-   pub fn create<P: AsRef<Path>>(output: P, num: u64) -> FilePart {
+   pub fn create<P: AsRef<Path>>(output: P, num: u64) -> io::Result<FilePart> {
       let path = FilePart::add_part_extension(output, num);
-      FilePart {
-         file: File::create(&path).unwrap(),
-         path: path
+      match File::create(&path) {
+         Ok(file) => Ok(FilePart { file, path }),
+         Err(f) => Err(FileError::wrap(f, "create", &path))
       }
    }
 
-   pub fn load_or_create<P: AsRef<Path>>(output: P, num: u64) -> FilePart {
+   pub fn load_or_create<P: AsRef<Path>>(output: P, num: u64) -> io::Result<FilePart> {
       let path = FilePart::add_part_extension(output, num);
-      let mut file = OpenOptions::new().write(true)
-                                       .create(true)
-                                       .open(&path)
-                                       .unwrap();
-      file.seek(SeekFrom::End(0)).unwrap();
-      FilePart {
-         file: file,
-         path: path
+      let file = OpenOptions::new().write(true)
+                                   .create(true)
+                                   .truncate(false)
+                                   .open(&path)
+                                   .and_then(|mut file| file.seek(SeekFrom::End(0)).map(|_| file));
+      match file {
+         Ok(file) => Ok(FilePart { file, path }),
+         Err(f) => Err(FileError::wrap(f, "open", &path))
       }
    }
 
-   pub fn open<P: AsRef<Path>>(input: P, num: u64) -> FilePart {
+   pub fn open<P: AsRef<Path>>(input: P, num: u64) -> io::Result<FilePart> {
       let path = FilePart::add_part_extension(input, num);
-      FilePart {
-         file: File::open(&path).unwrap(),
-         path: path
+      match File::open(&path) {
+         Ok(file) => Ok(FilePart { file, path }),
+         Err(f) => Err(FileError::wrap(f, "open", &path))
       }
    }
 
-   pub fn delete(self) {
+   pub fn delete(self) -> io::Result<()> {
       drop(self.file);
-      fs::remove_file(self.path).unwrap();
+      match fs::remove_file(&self.path) {
+         Ok(()) => Ok(()),
+         Err(f) => Err(FileError::wrap(f, "delete", &self.path))
+      }
+   }
+
+   /// Reserves disk space for `len` more bytes after the data the part already holds.  Returns
+   /// whether the space was reserved, see `util::preallocate()`.
+   pub fn preallocate(&self, len: u64) -> io::Result<bool> {
+      let offset = self.metadata()?.len();
+      util::preallocate(&self.file, offset, len)
+         .map_err(|f| FileError::wrap(f, "reserve space for", &self.path))
    }
 
    pub fn metadata(&self) -> io::Result<Metadata> {
-      self.file.metadata()
+      self.file.metadata().map_err(|f| FileError::wrap(f, "inspect", &self.path))
    }
 
-   fn add_part_extension<P: AsRef<Path>>(path: P, num: u64) -> PathBuf {
+   pub fn add_part_extension<P: AsRef<Path>>(path: P, num: u64) -> PathBuf {
       util::add_path_extension(path, &format!("part{}", num))
    }
 }
 
 impl Write for FilePart {
    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-      self.file.write(buf)
+      self.file.write(buf).map_err(|f| FileError::wrap(f, "write to", &self.path))
    }
 
    fn flush(&mut self) -> io::Result<()> {
-      self.file.flush()
+      self.file.flush().map_err(|f| FileError::wrap(f, "write to", &self.path))
    }
 }
 
 impl Read for FilePart {
    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-      self.file.read(buf)
+      self.file.read(buf).map_err(|f| FileError::wrap(f, "read", &self.path))
    }
 }
\ No newline at end of file
@@ -0,0 +1,331 @@
+// This file is part of rget.
+//
+// Copyright (C) 2016-2017 Arcterus (Alex Lyon) and rget contributors.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+use std::env;
+use std::io::Write;
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use pbr::{MultiBar, Pipe, ProgressBar, Units};
+
+use error::{self, Error, ErrorReason};
+
+const PRINT_DELAY: u64 = 100;
+/// The speed shown, and the time left estimated from it, is the average over this many seconds.
+const RATE_WINDOW: u64 = 5;
+/// Terminals narrower than this only get a single line of progress.
+const COMPACT_WIDTH: usize = 60;
+
+/// Something that happened during a download.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProgressEvent {
+   /// The download is about to start.  `length` is the size of the file, if it is known, and
+   /// `resumed` the number of bytes that were downloaded by an earlier, interrupted run.
+   Started { length: Option<u64>, parts: u64, resumed: u64 },
+   /// A part has started.  `length` is the number of bytes it is going to receive, if known, and
+   /// `resumed` the number of bytes of it that were downloaded by an earlier run.  A part that
+   /// was already complete starts with a `length` of 0 and finishes right away.
+   PartStarted { part: u64, length: Option<u64>, resumed: u64 },
+   /// A part has received `n` more bytes.
+   BytesWritten { part: u64, n: u64 },
+   /// A part failed and is about to be requested again.
+   PartRetry { part: u64 },
+   /// A part has finished, either because it is complete or because it failed.
+   PartFinished { part: u64, succeeded: bool },
+   /// All parts are complete and are being merged into the output file.
+   Merging,
+   /// The download has finished.
+   Done { succeeded: bool }
+}
+
+/// Receives the progress events of every download of a `Downloader`.
+///
+/// Events are delivered on the thread the download runs on, so listeners should not block.
+pub trait ProgressListener {
+   fn event(&mut self, event: &ProgressEvent);
+}
+
+/// Forwards the events to a channel, e.g. for a listener that runs on another thread.
+impl ProgressListener for Sender<ProgressEvent> {
+   fn event(&mut self, event: &ProgressEvent) {
+      // the receiver going away just means nobody is interested anymore
+      let _ = self.send(event.clone());
+   }
+}
+
+/// How progress is drawn on a terminal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressStyle {
+   /// Nothing is drawn.
+   Hidden,
+   /// A single line for the whole download.
+   Compact,
+   /// A line for the whole download followed by a bar for every part.
+   Full
+}
+
+/// Draws the progress of a download on a terminal: a bar for the whole download (unless there
+/// is only one part) and, unless compact, a bar for every part.
+pub struct ProgressBars {
+   total: Option<Bar>,
+   parts: Vec<Bar>,
+   name: String,
+   labels: Vec<String>,
+   unfinished: usize,
+   failed: bool,
+   listener: JoinHandle<()>
+}
+
+/// A single bar along with what is needed to show its speed and time left.
+struct Bar {
+   bar: ProgressBar<Pipe>,
+   state: &'static str,
+   current: u64,
+   total: Option<u64>,
+   rate: Rate
+}
+
+/// The average speed over the last `RATE_WINDOW` seconds.
+struct Rate {
+   start: Instant,
+   samples: VecDeque<(Instant, u64)>,
+   bytes: u64
+}
+
+impl ProgressBars {
+   /// Creates the bars for a download of `name` split into one part for every entry of
+   /// `labels`, and starts drawing them to `output` on another thread.
+   pub fn new(output: Box<dyn Write + Send>,
+              name: String,
+              labels: Vec<String>,
+              style: ProgressStyle) -> ProgressBars {
+      let mut mb = MultiBar::on(output);
+      let compact = style == ProgressStyle::Compact;
+      let total = if compact || labels.len() > 1 {
+         let mut total = Bar::new(mb.create_bar(100), "Total    ");
+         // the counter does not fit on a narrow terminal
+         total.bar.show_counter = !compact;
+         Some(total)
+      } else {
+         None
+      };
+      let parts = if compact {
+         vec![]
+      } else {
+         labels.iter().map(|_| Bar::new(mb.create_bar(100), "Waiting  ")).collect()
+      };
+
+      ProgressBars {
+         total,
+         parts,
+         name,
+         unfinished: labels.len(),
+         labels,
+         failed: false,
+         listener: thread::spawn(move || mb.listen())
+      }
+   }
+
+   /// Waits for the bars to be drawn for the last time.  Every part must have finished first.
+   pub fn join(self) -> error::Result<()> {
+      match self.listener.join() {
+         Ok(()) => Ok(()),
+         Err(f) => Err(Error::new(ErrorReason::FailedThread(f)))
+      }
+   }
+}
+
+impl ProgressListener for ProgressBars {
+   fn event(&mut self, event: &ProgressEvent) {
+      match *event {
+         ProgressEvent::Started { length, resumed, .. } => {
+            if let Some(ref mut total) = self.total {
+               total.start(length, resumed);
+            }
+         }
+         ProgressEvent::PartStarted { part, length, resumed } => {
+            if let Some(bar) = self.parts.get_mut(part as usize) {
+               bar.state = "Connected";
+               bar.start(length.map(|length| resumed + length), resumed);
+            }
+         }
+         ProgressEvent::BytesWritten { part, n } => {
+            if let Some(bar) = self.parts.get_mut(part as usize) {
+               bar.add(n);
+            }
+            if let Some(ref mut total) = self.total {
+               total.add(n);
+            }
+         }
+         ProgressEvent::PartRetry { part } => {
+            if let Some(bar) = self.parts.get_mut(part as usize) {
+               bar.state = "Retrying ";
+               bar.update();
+            }
+         }
+         ProgressEvent::PartFinished { part, succeeded } => {
+            if let Some(bar) = self.parts.get_mut(part as usize) {
+               bar.finish(succeeded, &self.labels[part as usize]);
+            }
+            self.failed |= !succeeded;
+            self.unfinished -= 1;
+            if self.unfinished == 0 {
+               if let Some(ref mut total) = self.total {
+                  total.finish(!self.failed, &self.name);
+               }
+            }
+         }
+         ProgressEvent::Merging | ProgressEvent::Done { .. } => {}
+      }
+   }
+}
+
+impl Bar {
+   fn new(mut bar: ProgressBar<Pipe>, state: &'static str) -> Bar {
+      bar.set_max_refresh_rate(Some(Duration::from_millis(PRINT_DELAY)));
+      bar.show_message = true;
+      bar.set_units(Units::Bytes);
+      // pbr counts bytes from an earlier run as if they had just been downloaded, so the speed
+      // and time left are shown as part of the message instead
+      bar.show_speed = false;
+      bar.show_time_left = false;
+      let mut bar = Bar {
+         bar,
+         state,
+         current: 0,
+         total: None,
+         rate: Rate::new()
+      };
+      bar.update();
+      bar
+   }
+
+   fn start(&mut self, total: Option<u64>, current: u64) {
+      self.total = total;
+      self.current = current;
+      self.bar.total = total.unwrap_or(u64::MAX);
+      self.update();
+      self.bar.set(current);
+   }
+
+   fn add(&mut self, n: u64) {
+      self.current += n;
+      self.rate.add(n);
+      self.update();
+      self.bar.add(n);
+   }
+
+   fn update(&mut self) {
+      let speed = self.rate.speed();
+      let left = match self.total {
+         Some(total) if speed > 0.0 => {
+            format_duration((total.saturating_sub(self.current) as f64 / speed) as u64)
+         }
+         _ => "--:--".to_string()
+      };
+      self.bar.message(&format!("{}: {:>11} {:>7} ", self.state, format_speed(speed), left));
+   }
+
+   fn finish(&mut self, succeeded: bool, label: &str) {
+      let status = if succeeded { "Completed" } else { "Failed   " };
+      self.bar.finish_print(&format!("{}: {}", status, label));
+   }
+}
+
+impl Rate {
+   fn new() -> Rate {
+      Rate {
+         start: Instant::now(),
+         samples: VecDeque::new(),
+         bytes: 0
+      }
+   }
+
+   fn add(&mut self, n: u64) {
+      let now = Instant::now();
+      self.samples.push_back((now, n));
+      self.bytes += n;
+      while let Some(&(time, n)) = self.samples.front() {
+         if now.duration_since(time) <= Duration::from_secs(RATE_WINDOW) {
+            break;
+         }
+         self.samples.pop_front();
+         self.bytes -= n;
+      }
+   }
+
+   /// Bytes per second.
+   fn speed(&self) -> f64 {
+      let window = self.start.elapsed().min(Duration::from_secs(RATE_WINDOW)).as_secs_f64();
+      if window > 0.0 {
+         self.bytes as f64 / window
+      } else {
+         0.0
+      }
+   }
+}
+
+fn format_speed(speed: f64) -> String {
+   let kb = 1024.0;
+   if speed >= kb * kb * kb {
+      format!("{:.2} GB/s", speed / (kb * kb * kb))
+   } else if speed >= kb * kb {
+      format!("{:.2} MB/s", speed / (kb * kb))
+   } else if speed >= kb {
+      format!("{:.2} KB/s", speed / kb)
+   } else {
+      format!("{:.0} B/s", speed)
+   }
+}
+
+fn format_duration(secs: u64) -> String {
+   if secs >= 3600 {
+      format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+   } else {
+      format!("{}:{:02}", secs / 60, secs % 60)
+   }
+}
+
+/// The style to use on a terminal that is `width` columns wide, if that is known.
+pub fn style_for_width(width: Option<usize>) -> ProgressStyle {
+   match width {
+      Some(width) if width < COMPACT_WIDTH => ProgressStyle::Compact,
+      _ => ProgressStyle::Full
+   }
+}
+
+/// The width of the terminal from `$COLUMNS` or, failing that, by asking the terminal on `fd`.
+pub fn terminal_width(fd: i32) -> Option<usize> {
+   env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok()).or_else(|| query_width(fd))
+}
+
+#[cfg(unix)]
+fn query_width(fd: i32) -> Option<usize> {
+   use libc::{ioctl, winsize, TIOCGWINSZ};
+
+   let mut size = winsize {
+      ws_row: 0,
+      ws_col: 0,
+      ws_xpixel: 0,
+      ws_ypixel: 0
+   };
+   // TIOCGWINSZ only writes to the winsize passed to it
+   let result = unsafe { ioctl(fd, TIOCGWINSZ, &mut size) };
+   if result == 0 && size.ws_col > 0 {
+      Some(size.ws_col as usize)
+   } else {
+      None
+   }
+}
+
+#[cfg(not(unix))]
+fn query_width(_fd: i32) -> Option<usize> {
+   None
+}
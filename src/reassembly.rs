@@ -0,0 +1,272 @@
+// This file is part of rget.
+//
+// Copyright (C) 2016-2017 Arcterus (Alex Lyon) and rget contributors.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes parts that are downloaded concurrently to a single stream in order.
+///
+/// The part at the front is written through as it arrives.  Parts that are ahead of it are held
+/// in memory, up to `memory_limit` bytes each, and spilled to a temporary file beyond that until
+/// every part before them has been written.
+pub struct Reassembler<W: Write> {
+   inner: Rc<RefCell<Inner<W>>>
+}
+
+struct Inner<W: Write> {
+   output: W,
+   /// The part currently written through to `output`.
+   current: usize,
+   parts: Vec<Pending>,
+   memory_limit: usize
+}
+
+/// Data of a part that cannot be written to the output yet.
+#[derive(Default)]
+struct Pending {
+   memory: Vec<u8>,
+   spill: Option<(File, PathBuf)>,
+   finished: bool
+}
+
+/// The writer for a single part of a `Reassembler`.
+pub struct PartWriter<W: Write> {
+   index: usize,
+   inner: Rc<RefCell<Inner<W>>>
+}
+
+impl<W: Write> Reassembler<W> {
+   pub fn new(output: W, parts: usize, memory_limit: usize) -> Reassembler<W> {
+      Reassembler {
+         inner: Rc::new(RefCell::new(Inner {
+            output,
+            current: 0,
+            parts: (0..parts).map(|_| Pending::default()).collect(),
+            memory_limit
+         }))
+      }
+   }
+
+   pub fn part(&self, index: usize) -> PartWriter<W> {
+      PartWriter {
+         index,
+         inner: self.inner.clone()
+      }
+   }
+
+   /// Marks part `index` as complete and writes out every part after it that can now be written.
+   pub fn finish(&self, index: usize) -> io::Result<()> {
+      let mut inner = self.inner.borrow_mut();
+      inner.parts[index].finished = true;
+      while inner.current < inner.parts.len() && inner.parts[inner.current].finished {
+         inner.current += 1;
+         if inner.current < inner.parts.len() {
+            let current = inner.current;
+            let pending = mem::take(&mut inner.parts[current]);
+            inner.parts[current].finished = pending.finished;
+            pending.write_to(&mut inner.output)?;
+         }
+      }
+      inner.output.flush()
+   }
+}
+
+impl<W: Write> Clone for Reassembler<W> {
+   fn clone(&self) -> Reassembler<W> {
+      Reassembler {
+         inner: self.inner.clone()
+      }
+   }
+}
+
+impl<W: Write> Write for PartWriter<W> {
+   fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      let mut inner = self.inner.borrow_mut();
+      if self.index == inner.current {
+         inner.output.write_all(buf)?;
+      } else {
+         let limit = inner.memory_limit;
+         inner.parts[self.index].push(buf, limit)?;
+      }
+      Ok(buf.len())
+   }
+
+   fn flush(&mut self) -> io::Result<()> {
+      self.inner.borrow_mut().output.flush()
+   }
+}
+
+impl Pending {
+   fn push(&mut self, buf: &[u8], memory_limit: usize) -> io::Result<()> {
+      if self.spill.is_none() && self.memory.len() + buf.len() <= memory_limit {
+         self.memory.extend_from_slice(buf);
+         return Ok(());
+      }
+      if self.spill.is_none() {
+         let path = env::temp_dir().join(format!("rget-{}-{}.spill",
+                                                 process::id(),
+                                                 SPILL_COUNTER.fetch_add(1, Ordering::SeqCst)));
+         let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+         self.spill = Some((file, path));
+      }
+      if let Some((ref mut file, _)) = self.spill {
+         file.write_all(buf)?;
+      }
+      Ok(())
+   }
+
+   fn write_to<W: Write>(mut self, output: &mut W) -> io::Result<()> {
+      output.write_all(&self.memory)?;
+      if let Some((ref mut file, _)) = self.spill {
+         file.seek(SeekFrom::Start(0))?;
+         io::copy(file, output)?;
+      }
+      Ok(())
+   }
+}
+
+impl Drop for Pending {
+   fn drop(&mut self) {
+      if let Some((_, ref path)) = self.spill {
+         let _ = fs::remove_file(path);
+      }
+   }
+}
+
+/// Writes a single part into an output shared with the other parts, starting at the offset the
+/// part begins at.
+pub struct SeekWriter<W: Write + Seek> {
+   output: Rc<RefCell<W>>,
+   offset: u64
+}
+
+impl<W: Write + Seek> SeekWriter<W> {
+   pub fn new(output: Rc<RefCell<W>>, offset: u64) -> SeekWriter<W> {
+      SeekWriter {
+         output,
+         offset
+      }
+   }
+}
+
+impl<W: Write + Seek> Write for SeekWriter<W> {
+   fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      let mut output = self.output.borrow_mut();
+      output.seek(SeekFrom::Start(self.offset))?;
+      let written = output.write(buf)?;
+      self.offset += written as u64;
+      Ok(written)
+   }
+
+   fn flush(&mut self) -> io::Result<()> {
+      self.output.borrow_mut().flush()
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use std::io::{Cursor, Write};
+
+   use super::*;
+
+   fn output(reassembler: &Reassembler<Vec<u8>>) -> Vec<u8> {
+      reassembler.inner.borrow().output.clone()
+   }
+
+   fn spill_path(reassembler: &Reassembler<Vec<u8>>, index: usize) -> Option<PathBuf> {
+      reassembler.inner.borrow().parts[index].spill.as_ref().map(|&(_, ref path)| path.clone())
+   }
+
+   #[test]
+   fn writes_parts_finished_in_order() {
+      let reassembler = Reassembler::new(vec![], 3, 16);
+      for (index, data) in [&b"abc"[..], b"def", b"gh"].iter().enumerate() {
+         reassembler.part(index).write_all(data).unwrap();
+         reassembler.finish(index).unwrap();
+      }
+      assert_eq!(output(&reassembler), b"abcdefgh");
+   }
+
+   #[test]
+   fn writes_the_current_part_through() {
+      let reassembler = Reassembler::new(vec![], 2, 16);
+      reassembler.part(1).write_all(b"later").unwrap();
+      reassembler.part(0).write_all(b"first ").unwrap();
+      assert_eq!(output(&reassembler), b"first ");
+      reassembler.finish(0).unwrap();
+      assert_eq!(output(&reassembler), b"first later");
+      reassembler.part(1).write_all(b" still").unwrap();
+      reassembler.finish(1).unwrap();
+      assert_eq!(output(&reassembler), b"first later still");
+   }
+
+   #[test]
+   fn holds_parts_finished_out_of_order() {
+      let reassembler = Reassembler::new(vec![], 3, 16);
+      reassembler.part(2).write_all(b"gh").unwrap();
+      reassembler.finish(2).unwrap();
+      reassembler.part(1).write_all(b"def").unwrap();
+      reassembler.finish(1).unwrap();
+      assert_eq!(output(&reassembler), b"");
+      reassembler.part(0).write_all(b"abc").unwrap();
+      assert_eq!(output(&reassembler), b"abc");
+      reassembler.finish(0).unwrap();
+      assert_eq!(output(&reassembler), b"abcdefgh");
+   }
+
+   #[test]
+   fn spills_parts_beyond_the_memory_limit() {
+      let reassembler = Reassembler::new(vec![], 2, 4);
+      let mut part = reassembler.part(1);
+      part.write_all(b"abc").unwrap();
+      assert_eq!(spill_path(&reassembler, 1), None);
+      part.write_all(b"defg").unwrap();
+      part.write_all(b"hi").unwrap();
+      let path = spill_path(&reassembler, 1).unwrap();
+      assert_eq!(fs::read(&path).unwrap(), b"defghi");
+      reassembler.finish(1).unwrap();
+
+      reassembler.part(0).write_all(b"0123").unwrap();
+      reassembler.finish(0).unwrap();
+      assert_eq!(output(&reassembler), b"0123abcdefghi");
+      assert!(!path.exists());
+   }
+
+   #[test]
+   fn removes_spill_files_of_unfinished_downloads() {
+      let reassembler = Reassembler::new(vec![], 3, 0);
+      reassembler.part(1).write_all(b"abc").unwrap();
+      reassembler.part(2).write_all(b"def").unwrap();
+      let paths = vec![spill_path(&reassembler, 1).unwrap(), spill_path(&reassembler, 2).unwrap()];
+      assert_ne!(paths[0], paths[1]);
+      assert!(paths.iter().all(|path| path.exists()));
+      drop(reassembler);
+      assert!(paths.iter().all(|path| !path.exists()));
+   }
+
+   #[test]
+   fn writes_parts_at_their_offsets() {
+      let output = Rc::new(RefCell::new(Cursor::new(vec![0; 6])));
+      let mut second = SeekWriter::new(output.clone(), 3);
+      let mut first = SeekWriter::new(output.clone(), 0);
+      second.write_all(b"de").unwrap();
+      first.write_all(b"ab").unwrap();
+      second.write_all(b"f").unwrap();
+      first.write_all(b"c").unwrap();
+      assert_eq!(output.borrow().get_ref(), b"abcdef");
+   }
+}
@@ -0,0 +1,84 @@
+// This file is part of rget.
+//
+// Copyright (C) 2016-2017 Arcterus (Alex Lyon) and rget contributors.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use url::Url;
+
+/// A summary of a finished download.
+#[derive(Clone, Debug)]
+pub struct DownloadReport {
+   /// Where the file was saved, or `None` if it was written to stdout or a sink.
+   pub path: Option<PathBuf>,
+   /// The URL the file was downloaded from after following any redirects.
+   pub url: Url,
+   /// The HTTP version the server answered the initial request with, if it did.
+   pub protocol: Option<String>,
+   /// Size of the remote file, if the server reported it.
+   pub length: Option<u64>,
+   /// Bytes received from the server by this run (compressed transfers count the encoded bytes).
+   pub bytes_transferred: u64,
+   /// Bytes left over from an interrupted earlier run that did not have to be downloaded again.
+   pub bytes_reused: u64,
+   /// Time from the start of the transfers until the file was complete.
+   pub duration: Duration,
+   /// Average throughput over `duration` in bytes per second.
+   pub average_throughput: f64,
+   /// Highest throughput of all parts combined in any one second, in bytes per second.
+   pub peak_throughput: f64,
+   /// Number of requests that had to be sent again.
+   pub retries: u64,
+   /// Whether the download was skipped because the output file already existed.
+   pub skipped: bool,
+   pub headers: ReportHeaders,
+   /// The checksum the file was verified against, if it was verified.
+   pub checksum: Option<String>,
+   pub parts: Vec<PartReport>
+}
+
+/// Response headers of the initial request that describe the file.
+#[derive(Clone, Debug, Default)]
+pub struct ReportHeaders {
+   pub content_type: Option<String>,
+   pub etag: Option<String>,
+   pub last_modified: Option<String>
+}
+
+/// A summary of a single part of a download.
+#[derive(Clone, Debug)]
+pub struct PartReport {
+   pub index: u64,
+   /// The byte range of the file the part covers, inclusive, if the file was split.
+   pub range: Option<(u64, u64)>,
+   pub bytes_transferred: u64,
+   pub bytes_reused: u64,
+   pub duration: Duration
+}
+
+impl DownloadReport {
+   /// The report for a download that was skipped as `path` already exists.
+   pub fn skipped(path: PathBuf, url: Url) -> DownloadReport {
+      DownloadReport {
+         path: Some(path),
+         url,
+         protocol: None,
+         length: None,
+         bytes_transferred: 0,
+         bytes_reused: 0,
+         duration: Duration::from_secs(0),
+         average_throughput: 0.0,
+         peak_throughput: 0.0,
+         retries: 0,
+         skipped: true,
+         headers: ReportHeaders::default(),
+         checksum: None,
+         parts: vec![]
+      }
+   }
+}
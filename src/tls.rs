@@ -0,0 +1,389 @@
+// This file is part of rget.
+//
+// Copyright (C) 2016-2017 Arcterus (Alex Lyon) and rget contributors.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use base64;
+use futures::Future;
+use native_tls::{self, Certificate, Pkcs12, Protocol, TlsConnector, TlsConnectorBuilder};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::{TlsConnectorExt, TlsStream};
+use url::Url;
+
+use error::{self, Error, ErrorReason};
+use network::DownloaderConfig;
+use proxy::Connecting;
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+/// Starts every PEM encoded section, whether it holds a certificate or a key.
+const PEM_MARKER: &str = "-----BEGIN ";
+const PIN_PREFIX: &str = "sha256//";
+/// Whether the public key a server presents can be checked against pinned ones.
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+const PINNING_SUPPORTED: bool = true;
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "ios"))]
+const PINNING_SUPPORTED: bool = false;
+/// Whether HTTP/2 can be offered to servers while the TLS connection is made.
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+const ALPN_SUPPORTED: bool = true;
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "ios"))]
+const ALPN_SUPPORTED: bool = false;
+
+/// A TLS protocol version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+   Tls10,
+   Tls11,
+   Tls12
+}
+
+impl TlsVersion {
+   /// The protocols a connection may use if it has to use at least this version.
+   fn protocols_from(self) -> Vec<Protocol> {
+      let mut protocols = vec![];
+      if self <= TlsVersion::Tls10 {
+         protocols.push(Protocol::Tlsv10);
+      }
+      if self <= TlsVersion::Tls11 {
+         protocols.push(Protocol::Tlsv11);
+      }
+      protocols.push(Protocol::Tlsv12);
+      protocols
+   }
+}
+
+impl FromStr for TlsVersion {
+   type Err = String;
+
+   fn from_str(input: &str) -> Result<TlsVersion, String> {
+      match input {
+         "1.0" => Ok(TlsVersion::Tls10),
+         "1.1" => Ok(TlsVersion::Tls11),
+         "1.2" => Ok(TlsVersion::Tls12),
+         _ => Err(format!("unsupported TLS version {} (expected 1.0, 1.1 or 1.2)", input))
+      }
+   }
+}
+
+impl Display for TlsVersion {
+   fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+      let version = match *self {
+         TlsVersion::Tls10 => "1.0",
+         TlsVersion::Tls11 => "1.1",
+         TlsVersion::Tls12 => "1.2"
+      };
+      write!(fmt, "TLS {}", version)
+   }
+}
+
+/// The TLS settings every HTTPS connection of a `Client` is made with.
+#[derive(Clone)]
+pub struct TlsSettings {
+   connector: TlsConnector,
+   /// Offers HTTP/2 as well as HTTP/1.1 to the server.  Only set if HTTP/2 is enabled and can be
+   /// negotiated on this platform.
+   http2_connector: Option<TlsConnector>,
+   verify_hostname: bool,
+   /// SHA-256 hashes of the public keys the server may present.  Any key is accepted if empty.
+   pins: Rc<Vec<Vec<u8>>>
+}
+
+/// The server presented a public key that matches none of the pinned ones.
+#[derive(Debug)]
+pub struct PinnedKeyMismatch {
+   host: String
+}
+
+impl TlsSettings {
+   /// Reads the TLS settings from `config`: the server must support at least
+   /// `config.min_tls_version`, the certificates in `config.ca_cert` are trusted as well as the
+   /// system's, the client certificate in `config.client_cert` is presented to servers that ask
+   /// for one, and the server must present one of the public keys in `config.pinned_public_key`.
+   /// HTTP/2 can be offered to servers unless `config.http2` is unset.
+   pub fn from_config(config: &DownloaderConfig) -> error::Result<TlsSettings> {
+      let pins = parse_pins(config)?;
+      let http2_connector = if config.http2 && ALPN_SUPPORTED {
+         Some(build_connector(config, true)?)
+      } else {
+         None
+      };
+
+      Ok(TlsSettings {
+         connector: build_connector(config, false)?,
+         http2_connector,
+         // the host name is only checked along with the certificate
+         verify_hostname: !config.accept_invalid_hostnames && !config.accept_invalid_certs,
+         pins: Rc::new(pins)
+      })
+   }
+
+   /// Whether servers have to present one of the pinned public keys.
+   pub fn pinned(&self) -> bool {
+      !self.pins.is_empty()
+   }
+
+   /// Whether HTTP/2 is offered to servers by `negotiate`.
+   pub fn offers_http2(&self) -> bool {
+      self.http2_connector.is_some()
+   }
+
+   /// Makes a TLS connection to the host of `url` over `stream`.  If public keys are pinned, the
+   /// connection fails with `PinnedKeyMismatch` before anything is sent over it unless the
+   /// server presented one of them.
+   pub fn connect<S>(&self, url: &Url, stream: S) -> Connecting<TlsStream<S>>
+      where S: AsyncRead + AsyncWrite + 'static {
+      self.handshake(&self.connector, url, stream)
+   }
+
+   /// Makes a TLS connection like `connect`, but offers HTTP/2 to the server as well.  Also
+   /// returns whether the server chose it over HTTP/1.1.
+   pub fn negotiate<S>(&self, url: &Url, stream: S) -> Connecting<(TlsStream<S>, bool)>
+      where S: AsyncRead + AsyncWrite + 'static {
+      let connector = self.http2_connector.as_ref().unwrap_or(&self.connector);
+      Box::new(self.handshake(connector, url, stream).map(|stream| {
+         let http2 = selected_protocol(stream.get_ref()).is_some_and(|protocol| protocol == b"h2");
+         (stream, http2)
+      }))
+   }
+
+   fn handshake<S>(&self, connector: &TlsConnector, url: &Url, stream: S) -> Connecting<TlsStream<S>>
+      where S: AsyncRead + AsyncWrite + 'static {
+      let host = url.host_str().unwrap_or("").trim_matches(|c| c == '[' || c == ']').to_string();
+      let handshake = if self.verify_hostname {
+         connector.connect_async(&host, stream)
+      } else {
+         connector.danger_connect_async_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
+      };
+      let pins = self.pins.clone();
+      Box::new(handshake.map_err(io::Error::other).and_then(move |stream| {
+         let hash = public_key_hash(stream.get_ref());
+         if pins.is_empty() || hash.is_some_and(|hash| pins.contains(&hash)) {
+            Ok(stream)
+         } else {
+            Err(io::Error::other(PinnedKeyMismatch { host }))
+         }
+      }))
+   }
+}
+
+impl Display for PinnedKeyMismatch {
+   fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+      write!(fmt, "public key of {} does not match the pinned public key", self.host)
+   }
+}
+
+impl ::std::error::Error for PinnedKeyMismatch {}
+
+/// Builds a connector with the settings from `config`: the server must support at least
+/// `config.min_tls_version`, the certificates in `config.ca_cert` are trusted as well as the
+/// system's, and the client certificate in `config.client_cert` is presented to servers that ask
+/// for one.  HTTP/2 is offered to servers if `offer_http2` is set.
+fn build_connector(config: &DownloaderConfig, offer_http2: bool) -> error::Result<TlsConnector> {
+   let mut builder = TlsConnector::builder().map_err(tls_error)?;
+   if let Some(version) = config.min_tls_version {
+      builder.supported_protocols(&version.protocols_from()).map_err(tls_error)?;
+   }
+   if config.accept_invalid_certs {
+      accept_invalid_certs(&mut builder)?;
+   }
+   if let Some(ref path) = config.ca_cert {
+      for der in load_certificates(path)? {
+         let cert = match Certificate::from_der(&der) {
+            Ok(cert) => cert,
+            Err(_) => return Err(Error::new(ErrorReason::InvalidCertificate(path.clone())))
+         };
+         builder.add_root_certificate(cert).map_err(tls_error)?;
+      }
+   }
+   if let Some(ref path) = config.client_cert {
+      load_identity(&mut builder, path, config)?;
+   }
+   if offer_http2 {
+      offer_protocols(&mut builder, &[b"h2", b"http/1.1"])?;
+   }
+   builder.build().map_err(tls_error)
+}
+
+/// Reads the `sha256//<base64>` hashes (separated by `;`, as with curl's `--pinnedpubkey`) in
+/// `config.pinned_public_key`.
+fn parse_pins(config: &DownloaderConfig) -> error::Result<Vec<Vec<u8>>> {
+   let mut pins = vec![];
+   let input = config.pinned_public_key.as_ref().map_or("", |pins| &pins[..]);
+   for pin in input.split(';').map(str::trim).filter(|pin| !pin.is_empty()) {
+      if !pin.starts_with(PIN_PREFIX) {
+         return Err(Error::new(ErrorReason::InvalidConfig(
+                               "pinned public keys must be given as sha256//<base64 hash>")));
+      }
+      match base64::decode(&pin[PIN_PREFIX.len()..]) {
+         Ok(hash) => pins.push(hash),
+         Err(_) => return Err(Error::new(ErrorReason::InvalidConfig(
+                                         "pinned public key hash is not valid base64")))
+      }
+   }
+   if !pins.is_empty() && !PINNING_SUPPORTED {
+      return Err(Error::new(ErrorReason::InvalidConfig(
+                            "public key pinning is not supported on this platform")));
+   }
+   Ok(pins)
+}
+
+/// Presents the client certificate at `path` to servers that ask for one.  A PKCS#12 archive is
+/// decrypted with `config.client_cert_password`.  A PEM encoded certificate is used with the
+/// private key in `config.client_key`, or in the same file if that is not set.
+fn load_identity(builder: &mut TlsConnectorBuilder,
+                 path: &Path,
+                 config: &DownloaderConfig) -> error::Result<()> {
+   let mut data = vec![];
+   if let Err(f) = File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+      return Err(Error::new(ErrorReason::IO(f)));
+   }
+   if String::from_utf8_lossy(&data).contains(PEM_MARKER) {
+      let key = config.client_key.as_ref().map_or(path, |key| key.as_path());
+      return load_pem_identity(builder, path, key);
+   }
+   if config.client_key.is_some() {
+      return Err(Error::new(ErrorReason::InvalidConfig(
+                            "a private key can only be given with a PEM client certificate")));
+   }
+
+   let password = config.client_cert_password.as_ref().map_or("", |password| &password[..]);
+   let identity = match Pkcs12::from_der(&data, password) {
+      Ok(identity) => identity,
+      Err(_) => return Err(Error::new(ErrorReason::InvalidCertificate(path.to_path_buf())))
+   };
+   builder.identity(identity).map_err(tls_error)?;
+   Ok(())
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+fn load_pem_identity(builder: &mut TlsConnectorBuilder,
+                     cert: &Path,
+                     key: &Path) -> error::Result<()> {
+   use native_tls::backend::openssl::TlsConnectorBuilderExt;
+   use openssl::x509::X509_FILETYPE_PEM;
+
+   let context = builder.builder_mut().builder_mut();
+   if context.set_certificate_chain_file(cert).is_err() {
+      return Err(Error::new(ErrorReason::InvalidCertificate(cert.to_path_buf())));
+   }
+   if context.set_private_key_file(key, X509_FILETYPE_PEM).is_err()
+         || context.check_private_key().is_err() {
+      return Err(Error::new(ErrorReason::InvalidCertificate(key.to_path_buf())));
+   }
+   Ok(())
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "ios"))]
+fn load_pem_identity(_builder: &mut TlsConnectorBuilder,
+                     _cert: &Path,
+                     _key: &Path) -> error::Result<()> {
+   Err(Error::new(ErrorReason::InvalidConfig(
+                  "PEM client certificates are not supported on this platform, use PKCS#12")))
+}
+
+/// Reads a DER encoded certificate or a bundle of PEM encoded certificates.
+fn load_certificates(path: &Path) -> error::Result<Vec<Vec<u8>>> {
+   let mut data = vec![];
+   if let Err(f) = File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+      return Err(Error::new(ErrorReason::IO(f)));
+   }
+
+   let text = String::from_utf8_lossy(&data);
+   if !text.contains(PEM_BEGIN) {
+      return Ok(vec![data]);
+   }
+
+   let mut certs = vec![];
+   let mut rest = &text[..];
+   while let Some(start) = rest.find(PEM_BEGIN) {
+      rest = &rest[start + PEM_BEGIN.len()..];
+      let end = match rest.find(PEM_END) {
+         Some(end) => end,
+         None => return Err(Error::new(ErrorReason::InvalidCertificate(path.to_path_buf())))
+      };
+      let encoded: String = rest[..end].split_whitespace().collect();
+      match base64::decode(&encoded) {
+         Ok(der) => certs.push(der),
+         Err(_) => return Err(Error::new(ErrorReason::InvalidCertificate(path.to_path_buf())))
+      }
+      rest = &rest[end + PEM_END.len()..];
+   }
+   Ok(certs)
+}
+
+/// Makes connections made with `builder` accept any certificate, so that only a pinned public key
+/// still identifies the server.
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+fn accept_invalid_certs(builder: &mut TlsConnectorBuilder) -> error::Result<()> {
+   use native_tls::backend::openssl::TlsConnectorBuilderExt;
+   use openssl::ssl::SSL_VERIFY_NONE;
+
+   builder.builder_mut().builder_mut().set_verify(SSL_VERIFY_NONE);
+   Ok(())
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "ios"))]
+fn accept_invalid_certs(_builder: &mut TlsConnectorBuilder) -> error::Result<()> {
+   Err(Error::new(ErrorReason::InvalidConfig(
+                  "accepting invalid certificates is not supported on this platform")))
+}
+
+/// Offers `protocols` to servers through ALPN, most preferred first.
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+fn offer_protocols(builder: &mut TlsConnectorBuilder, protocols: &[&[u8]]) -> error::Result<()> {
+   use native_tls::backend::openssl::TlsConnectorBuilderExt;
+
+   if builder.builder_mut().builder_mut().set_alpn_protocols(protocols).is_err() {
+      return Err(Error::new(ErrorReason::InvalidConfig("could not offer HTTP/2 to servers")));
+   }
+   Ok(())
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "ios"))]
+fn offer_protocols(_builder: &mut TlsConnectorBuilder, _protocols: &[&[u8]]) -> error::Result<()> {
+   Ok(())
+}
+
+/// The protocol the server chose through ALPN on `stream`, if any.
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+fn selected_protocol<S>(stream: &native_tls::TlsStream<S>) -> Option<Vec<u8>> {
+   use native_tls::backend::openssl::TlsStreamExt;
+
+   stream.raw_stream().ssl().selected_alpn_protocol().map(|protocol| protocol.to_vec())
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "ios"))]
+fn selected_protocol<S>(_stream: &native_tls::TlsStream<S>) -> Option<Vec<u8>> {
+   None
+}
+
+/// The SHA-256 hash of the public key the server presented on `stream`.
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+fn public_key_hash<S>(stream: &native_tls::TlsStream<S>) -> Option<Vec<u8>> {
+   use native_tls::backend::openssl::TlsStreamExt;
+   use openssl::hash::{self, MessageDigest};
+
+   let cert = stream.raw_stream().ssl().peer_certificate()?;
+   let spki = cert.public_key().and_then(|key| key.public_key_to_der()).ok()?;
+   hash::hash2(MessageDigest::sha256(), &spki).map(|digest| digest.to_vec()).ok()
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos", target_os = "ios"))]
+fn public_key_hash<S>(_stream: &native_tls::TlsStream<S>) -> Option<Vec<u8>> {
+   None
+}
+
+fn tls_error(err: native_tls::Error) -> Error {
+   Error::new(ErrorReason::Tls(err))
+}
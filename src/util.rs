@@ -7,10 +7,94 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
 use std::path::{Path, PathBuf};
 
 pub fn add_path_extension<P: AsRef<Path>>(path: P, ext: &str) -> PathBuf {
    let mut file_ext = path.as_ref().extension().unwrap_or(OsStr::new("")).to_os_string();
    file_ext.push(OsStr::new(&format!(".{}", ext)));
    path.as_ref().with_extension(file_ext)
-}
\ No newline at end of file
+}
+
+/// Appends `.<suffix>` to the file name of `path`, e.g. `file` and `file.tar.gz` become `file.1`
+/// and `file.tar.gz.1`.
+pub fn add_suffix<P: AsRef<Path>>(path: P, suffix: &str) -> PathBuf {
+   let mut name = path.as_ref().as_os_str().to_os_string();
+   name.push(format!(".{}", suffix));
+   PathBuf::from(name)
+}
+
+/// Renames the file at `path` (if there is one) to the first of `<path>.1`, `<path>.2`, ... that
+/// does not exist yet and returns the new path.
+pub fn backup_file<P: AsRef<Path>>(path: P) -> io::Result<Option<PathBuf>> {
+   let path = path.as_ref();
+   if !path.exists() {
+      return Ok(None);
+   }
+   let mut num = 1u64;
+   loop {
+      let backup = add_suffix(path, &num.to_string());
+      if !backup.exists() {
+         return fs::rename(path, &backup).map(|_| Some(backup));
+      }
+      num += 1;
+   }
+}
+
+/// The number of bytes available to unprivileged users on the filesystem `path` is on, or `None`
+/// if this cannot be found out on this platform.
+#[cfg(unix)]
+pub fn available_space<P: AsRef<Path>>(path: P) -> io::Result<Option<u64>> {
+   use std::ffi::CString;
+   use std::mem;
+   use std::os::unix::ffi::OsStrExt;
+   use libc::statvfs;
+
+   let path = match CString::new(path.as_ref().as_os_str().as_bytes()) {
+      Ok(m) => m,
+      Err(f) => return Err(io::Error::new(io::ErrorKind::InvalidInput, f))
+   };
+   // statvfs only writes to the struct passed to it, which is plain data
+   let mut stats: statvfs = unsafe { mem::zeroed() };
+   if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+      return Err(io::Error::last_os_error());
+   }
+   Ok(Some(stats.f_bavail as u64 * stats.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available_space<P: AsRef<Path>>(_path: P) -> io::Result<Option<u64>> {
+   Ok(None)
+}
+
+/// Reserves disk space for `len` bytes of `file` after its first `offset` bytes without changing
+/// its length, so data appended to it later cannot run out of space.  Returns whether the space
+/// was reserved, as not every platform and filesystem supports this.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+   use std::os::unix::io::AsRawFd;
+   use libc::{fallocate, off_t, EOPNOTSUPP, FALLOC_FL_KEEP_SIZE};
+
+   if len == 0 {
+      return Ok(true);
+   }
+   let result = unsafe {
+      fallocate(file.as_raw_fd(), FALLOC_FL_KEEP_SIZE, offset as off_t, len as off_t)
+   };
+   if result == 0 {
+      Ok(true)
+   } else {
+      let err = io::Error::last_os_error();
+      if err.raw_os_error() == Some(EOPNOTSUPP) {
+         Ok(false)
+      } else {
+         Err(err)
+      }
+   }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_file: &File, _offset: u64, _len: u64) -> io::Result<bool> {
+   Ok(false)
+}